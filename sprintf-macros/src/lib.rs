@@ -0,0 +1,399 @@
+//! Compile-time checked `sprintf!` for the [sprintf](https://docs.rs/sprintf)
+//! crate
+//!
+//! The format string is parsed when the macro is expanded, so a malformed
+//! format string, the wrong number of arguments, or an argument that can't
+//! be formatted with its conversion is a compile error:
+//!
+//! ```compile_fail
+//! use sprintf_macros::sprintf;
+//! let s = sprintf!("%d items", "three");
+//! ```
+//!
+//! The argument types are checked with the marker traits in
+//! [sprintf::checked]. Both crates need to be dependencies.
+
+use std::ops::Range;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote, quote_spanned};
+use sprintf::parser::{
+    parse_format_string, ConversionSpecifier, ConversionType, FormatElement, FormatElements,
+    NumericParam,
+};
+use sprintf::PrintfError;
+use syn::{
+    parse::{Parse, ParseStream},
+    spanned::Spanned,
+    Expr, LitStr, Token,
+};
+
+/// Format a string, checking the format string and the arguments at compile
+/// time
+///
+/// Takes the same arguments as `sprintf::sprintf!`, but the format string
+/// must be a string literal. It also returns a `sprintf::Result<String>`,
+/// since some errors can only be found at runtime, like a `&CStr` argument
+/// that isn't valid UTF-8.
+///
+///     use sprintf_macros::sprintf;
+///     let s = sprintf!("%s = %*d", "forty-two", 4, 42).unwrap();
+///     assert_eq!(s, "forty-two =   42");
+///
+/// Type errors point at the offending argument, and name it and its
+/// conversion. Other errors point at the conversion specifier (or, where the
+/// compiler can't point into the format string, at all of it), or at an
+/// argument that isn't used.
+///
+/// ```compile_fail
+/// # use sprintf_macros::sprintf;
+/// let s = sprintf!("%d + %d", 1);
+/// ```
+///
+/// ```compile_fail
+/// # use sprintf_macros::sprintf;
+/// let s = sprintf!("%.2f", 1);
+/// ```
+#[proc_macro]
+pub fn sprintf(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as Input);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// The arguments of the macro: a format string and a list of expressions
+struct Input {
+    format: LitStr,
+    args: Vec<Expr>,
+}
+
+impl Parse for Input {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let format = input.parse()?;
+        let mut args = Vec::new();
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+            args.push(input.parse()?);
+        }
+        Ok(Input { format, args })
+    }
+}
+
+/// A use of an argument by a conversion (or its `*` width or precision)
+struct ArgUse {
+    index: usize,
+    /// The marker trait in `sprintf::checked` the argument must implement
+    check: &'static str,
+    /// What the argument must be, for the error message
+    expected: &'static str,
+    /// `Some("width")` or `Some("precision")` for a `*` parameter
+    param: Option<&'static str>,
+    specifier: Range<usize>,
+}
+
+fn expand(input: &Input) -> syn::Result<TokenStream2> {
+    let format = input.format.value();
+    let elements = parse_elements(&input.format, &format)?;
+    // numbered arguments are checked on the whole format string
+    parse_format_string(&format).map_err(|e| parse_error(&input.format, &format, e))?;
+
+    // find the argument for each conversion and the type it needs
+    let mut uses = Vec::new();
+    let mut next_arg = 0;
+    let mut arg_index = |arg_number: Option<usize>| match arg_number {
+        Some(n) => n - 1,
+        None => {
+            next_arg += 1;
+            next_arg - 1
+        }
+    };
+    for (element, range) in &elements {
+        let spec = match element {
+            FormatElement::Format(spec) => spec,
+            FormatElement::Verbatim(_) => continue,
+        };
+        let (check, expected) = match spec.conversion_type {
+            ConversionType::DecInt
+            | ConversionType::UnsignedInt
+            | ConversionType::OctInt
+            | ConversionType::HexIntLower
+            | ConversionType::HexIntUpper
+            | ConversionType::BinIntLower
+            | ConversionType::BinIntUpper => INT,
+            ConversionType::SciFloatLower
+            | ConversionType::SciFloatUpper
+            | ConversionType::DecFloatLower
+            | ConversionType::DecFloatUpper
+            | ConversionType::CompactFloatLower
+            | ConversionType::CompactFloatUpper
+            | ConversionType::HexFloatLower
+            | ConversionType::HexFloatUpper => ("FloatArg", "a floating point number"),
+            ConversionType::Char => ("CharArg", "a character"),
+            ConversionType::String => ("StrArg", "a string"),
+            ConversionType::Pointer => ("PtrArg", "a pointer"),
+            ConversionType::Count => ("CountArg", "a `Cell` of an integer or an `AtomicUsize`"),
+            ConversionType::PercentSign => continue,
+        };
+        for (param, name) in [(spec.width, "width"), (spec.precision, "precision")] {
+            let arg_number = match param {
+                NumericParam::Literal(_) => continue,
+                NumericParam::FromArgument => None,
+                NumericParam::FromNumberedArgument(n) => Some(n),
+            };
+            uses.push(ArgUse {
+                index: arg_index(arg_number),
+                check: INT.0,
+                expected: INT.1,
+                param: Some(name),
+                specifier: range.clone(),
+            });
+        }
+        uses.push(ArgUse {
+            index: arg_index(spec.arg_number),
+            check,
+            expected,
+            param: None,
+            specifier: range.clone(),
+        });
+    }
+
+    // check the number of arguments
+    if let Some(missing) = uses.iter().find(|u| u.index >= input.args.len()) {
+        let text = &format[missing.specifier.clone()];
+        return Err(syn::Error::new(
+            specifier_span(&input.format, &format, missing.specifier.clone()),
+            format!(
+                "`{}` needs argument {}, but there are only {}",
+                text,
+                missing.index + 1,
+                input.args.len()
+            ),
+        ));
+    }
+    let n_args = uses.iter().map(|u| u.index + 1).max().unwrap_or(0);
+    if let Some(extra) = input.args.get(n_args) {
+        return Err(syn::Error::new(
+            extra.span(),
+            format!(
+                "argument {} is never used by the format string, which takes {}",
+                n_args + 1,
+                n_args
+            ),
+        ));
+    }
+
+    // type errors point at the argument, and name the conversion: each check
+    // is a trait with its own message, implemented by the types implementing
+    // the marker trait
+    let args = &input.args;
+    let names: Vec<_> = args
+        .iter()
+        .enumerate()
+        .map(|(i, arg)| format_ident!("__arg{}", i, span = arg.span()))
+        .collect();
+    let checks = uses.iter().map(|u| {
+        let check = format_ident!("{}", u.check);
+        let name = &names[u.index];
+        let text = &format[u.specifier.clone()];
+        let role = match u.param {
+            Some(param) => format!("the {} of `{}`", param, text),
+            None => format!("`{}`", text),
+        };
+        let message = format!(
+            "argument {} must be {} for {}, but it is a `{{Self}}`",
+            u.index + 1,
+            u.expected,
+            role
+        );
+        let label = format!("argument {} of {}", u.index + 1, role);
+        quote_spanned! {name.span()=>
+            {
+                #[diagnostic::on_unimplemented(message = #message, label = #label)]
+                trait Check {}
+                impl<T: ::sprintf::checked::#check + ?::core::marker::Sized> Check for T {}
+                fn check<T: Check + ?::core::marker::Sized>(_: &T) {}
+                check(#name);
+            }
+        }
+    });
+    let elements = elements.iter().map(|(element, _)| match element {
+        FormatElement::Verbatim(s) => quote!(::sprintf::parser::FormatElement::Verbatim(#s)),
+        FormatElement::Format(spec) => {
+            let spec = spec_tokens(spec);
+            quote!(::sprintf::parser::FormatElement::Format(#spec))
+        }
+    });
+    Ok(quote! {
+        match (#(&(#args),)*) {
+            (#(#names,)*) => {
+                #(#checks)*
+                ::sprintf::vsprintfp(
+                    &[#(#elements),*],
+                    &[#(#names as &dyn ::sprintf::Printf),*],
+                )
+            }
+        }
+    })
+}
+
+/// The marker trait and the description of an integer argument
+const INT: (&str, &str) = ("IntArg", "an integer");
+
+/// Parse the format string, finding the range of each element in it
+fn parse_elements<'a>(
+    lit: &LitStr,
+    format: &'a str,
+) -> syn::Result<Vec<(FormatElement<'a>, Range<usize>)>> {
+    let mut elements = Vec::new();
+    let mut iter = FormatElements::new(format);
+    loop {
+        let start = iter.offset();
+        match iter.next() {
+            Some(Ok(element)) => elements.push((element, start..iter.offset())),
+            Some(Err(e)) => return Err(parse_error(lit, format, e)),
+            None => return Ok(elements),
+        }
+    }
+}
+
+/// The compile error for an error parsing the format string, pointing at
+/// the invalid conversion specifier
+fn parse_error(lit: &LitStr, format: &str, e: PrintfError) -> syn::Error {
+    match e {
+        PrintfError::ParseError(e) => syn::Error::new(
+            specifier_span(lit, format, e.span()),
+            format!("{}: `{}`", e.reason, &format[e.span()]),
+        ),
+        e => syn::Error::new(lit.span(), e),
+    }
+}
+
+/// The span of part of the format string, if the compiler can point into
+/// the literal, otherwise the span of the whole literal
+fn specifier_span(lit: &LitStr, format: &str, range: Range<usize>) -> Span {
+    let token = lit.token();
+    source_offsets(&token.to_string(), format)
+        .and_then(|offsets| token.subspan(offsets[range.start]..offsets[range.end]))
+        .unwrap_or_else(|| lit.span())
+}
+
+/// The offset in the source of the string literal `token` of each byte of
+/// its value `value`, and of its end, or `None` if it can't be followed
+fn source_offsets(token: &str, value: &str) -> Option<Vec<usize>> {
+    let mut offsets = Vec::with_capacity(value.len() + 1);
+    let hashes = token
+        .strip_prefix('r')
+        .map(|raw| raw.len() - raw.trim_start_matches('#').len());
+    let quote = hashes.map_or(0, |n| n + 1);
+    if token.as_bytes().get(quote) != Some(&b'"') {
+        return None;
+    }
+    let body = &token[quote + 1..token.len() - 1 - hashes.unwrap_or(0)];
+    let mut chars = body.char_indices().peekable();
+    while let Some((pos, c)) = chars.next() {
+        let len = match c {
+            // raw strings have no escapes
+            '\\' if hashes.is_none() => match chars.next()?.1 {
+                'x' => {
+                    chars.next()?;
+                    chars.next()?;
+                    1
+                }
+                'u' => {
+                    let mut code = String::new();
+                    for (_, c) in chars.by_ref().skip(1) {
+                        if c == '}' {
+                            break;
+                        }
+                        code.push(c);
+                    }
+                    char::from_u32(u32::from_str_radix(&code.replace('_', ""), 16).ok()?)?
+                        .len_utf8()
+                }
+                // a line continuation, skipping the whitespace that follows
+                '\n' | '\r' => {
+                    while chars.next_if(|&(_, c)| c.is_whitespace()).is_some() {}
+                    0
+                }
+                _ => 1,
+            },
+            // a CRLF line ending is a single newline
+            '\r' if chars.peek().map(|&(_, c)| c) == Some('\n') => 0,
+            c => c.len_utf8(),
+        };
+        offsets.extend((0..len).map(|_| quote + 1 + pos));
+    }
+    offsets.push(token.len() - 1 - hashes.unwrap_or(0));
+    // the value must be what was found
+    if offsets.len() == value.len() + 1 {
+        Some(offsets)
+    } else {
+        None
+    }
+}
+
+/// The expression constructing `spec`
+fn spec_tokens(spec: &ConversionSpecifier) -> TokenStream2 {
+    let ConversionSpecifier {
+        alt_form,
+        zero_pad,
+        left_adj,
+        space_sign,
+        force_sign,
+        thousands_grouping,
+        locale_digits,
+        width,
+        precision,
+        length_modifier,
+        conversion_type,
+        conversion_char,
+        arg_number,
+    } = *spec;
+    let width = enum_tokens("NumericParam", width);
+    let precision = enum_tokens("NumericParam", precision);
+    let length_modifier = match length_modifier {
+        Some(len) => {
+            let len = enum_tokens("LengthModifier", len);
+            quote!(::core::option::Option::Some(#len))
+        }
+        None => quote!(::core::option::Option::None),
+    };
+    let conversion_type = enum_tokens("ConversionType", conversion_type);
+    let arg_number = match arg_number {
+        Some(n) => quote!(::core::option::Option::Some(#n)),
+        None => quote!(::core::option::Option::None),
+    };
+    quote! {
+        ::sprintf::parser::ConversionSpecifier {
+            alt_form: #alt_form,
+            zero_pad: #zero_pad,
+            left_adj: #left_adj,
+            space_sign: #space_sign,
+            force_sign: #force_sign,
+            thousands_grouping: #thousands_grouping,
+            locale_digits: #locale_digits,
+            width: #width,
+            precision: #precision,
+            length_modifier: #length_modifier,
+            conversion_type: #conversion_type,
+            conversion_char: #conversion_char,
+            arg_number: #arg_number,
+        }
+    }
+}
+
+/// The expression for a value of one of the parser's enums, whose `Debug`
+/// output is the expression without the type
+fn enum_tokens(type_name: &str, value: impl std::fmt::Debug) -> TokenStream2 {
+    let type_name = format_ident!("{}", type_name);
+    let value: TokenStream2 = format!("{:?}", value)
+        .parse()
+        .expect("Debug output of an enum is a valid expression");
+    quote!(::sprintf::parser::#type_name::#value)
+}
@@ -0,0 +1,53 @@
+use std::cell::Cell;
+
+use sprintf::{ArgContext, Printf, PrintfError};
+use sprintf_macros::sprintf;
+
+#[test]
+fn test_same_as_runtime() {
+    assert_eq!(
+        sprintf!("%d %5.2f|%-4s|%c %#x %%", -3, 2.5, "ab", 'z', 255_u8),
+        sprintf::sprintf!("%d %5.2f|%-4s|%c %#x %%", -3, 2.5, "ab", 'z', 255_u8)
+    );
+    assert_eq!(
+        sprintf!("%2$s %1$*3$d %2$s", 7, "x", 4).unwrap(),
+        "x    7 x"
+    );
+    // an `int` is converted to `unsigned char`
+    let c = std::num::NonZeroI32::new(0x63).unwrap();
+    assert_eq!(sprintf!("%c%c%-2c|", 65, 0x62_i16, c).unwrap(), "Abc |");
+    assert_eq!(
+        sprintf!("%c%c%-2c|", 65, 0x62_i16, c),
+        sprintf::sprintf!("%c%c%-2c|", 65, 0x62_i16, c)
+    );
+    assert_eq!(sprintf!("no arguments").unwrap(), "no arguments");
+    assert_eq!(sprintf!("%s", String::from("owned"),).unwrap(), "owned");
+}
+
+#[test]
+fn test_argument_types() {
+    let count = Cell::new(0);
+    let name: Option<&str> = None;
+    let dynamic: Box<dyn Printf> = Box::new(1.5);
+    let null = std::ptr::null::<u8>();
+    assert_eq!(
+        sprintf!(
+            "%s %d %.1f %p %ls%n",
+            name,
+            &&42_i128,
+            dynamic,
+            null,
+            &[0x41_u16][..],
+            count
+        )
+        .unwrap(),
+        "(null) 42 1.5 (nil) A"
+    );
+    assert_eq!(count.get(), 21);
+    // only known at runtime
+    let dynamic: Box<dyn Printf> = Box::new("text");
+    assert!(matches!(
+        sprintf!("%d", dynamic),
+        Err(PrintfError::WrongType(Some(ArgContext { index: 0, .. })))
+    ));
+}
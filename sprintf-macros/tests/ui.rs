@@ -0,0 +1,6 @@
+// The error messages and their spans, checked against the `.stderr` files
+// in `tests/ui`. Run with `TRYBUILD=overwrite` to update them.
+#[test]
+fn ui() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,5 @@
+use sprintf_macros::sprintf;
+
+fn main() {
+    let _ = sprintf!("%d\n", 1, 2);
+}
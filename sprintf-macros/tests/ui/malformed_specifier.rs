@@ -0,0 +1,5 @@
+use sprintf_macros::sprintf;
+
+fn main() {
+    let _ = sprintf!("\t%5.2y|", 1.0);
+}
@@ -0,0 +1,5 @@
+use sprintf_macros::sprintf;
+
+fn main() {
+    let _ = sprintf!("%d + %*d", 1, 2);
+}
@@ -0,0 +1,5 @@
+use sprintf_macros::sprintf;
+
+fn main() {
+    let _ = sprintf!(r"%1$d %d", 1, 2);
+}
@@ -0,0 +1,6 @@
+use sprintf_macros::sprintf;
+
+fn main() {
+    let _ = sprintf!("%d %d %d\n", 1, "two", 3);
+    let _ = sprintf!("%s has %*.*f items", "cart", 1.5, 2, 3.0);
+}
@@ -0,0 +1,231 @@
+//! A cache of parsed format strings
+//!
+//! With the `cache` feature, [vsprintf](crate::vsprintf) and
+//! [vwritef](crate::vwritef) (and the [FormatOptions](crate::FormatOptions)
+//! methods of the same names) look up the format string here instead of
+//! parsing it on every call. Each thread has its own cache, holding up to
+//! [capacity](CacheStats::capacity) format strings; when it is full, the
+//! least recently used one is dropped.
+//!
+//!     use sprintf::{cache, sprintf};
+//!     cache::prewarm(["%s: %d\n", "%5.1f%%"]).unwrap();
+//!     assert!(cache::contains("%s: %d\n"));
+//!     let s = sprintf!("%s: %d\n", "errors", 0).unwrap();
+//!     assert_eq!(cache::stats().hits, 1);
+//!
+//! Invalid format strings are not cached.
+
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::{CompiledFormat, Result};
+
+/// The number of format strings each thread's cache holds by default
+pub const DEFAULT_CAPACITY: usize = 64;
+
+/// Statistics of the cache of the current thread (see [stats])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The number of format strings in the cache
+    pub len: usize,
+    /// The maximum number of format strings in the cache
+    pub capacity: usize,
+    /// The number of lookups that found the format string in the cache
+    pub hits: u64,
+    /// The number of lookups that had to parse the format string
+    pub misses: u64,
+}
+
+struct Cache {
+    /// The index in `nodes` of each format string
+    index: HashMap<Rc<str>, usize>,
+    /// The entries, linked in the order they were used
+    nodes: Vec<Node>,
+    /// The most recently used entry
+    newest: Option<usize>,
+    /// The least recently used entry, dropped first
+    oldest: Option<usize>,
+    capacity: usize,
+    hits: u64,
+    misses: u64,
+}
+
+struct Node {
+    format: Rc<str>,
+    compiled: Rc<CompiledFormat>,
+    /// The entry used after this one
+    newer: Option<usize>,
+    /// The entry used before this one
+    older: Option<usize>,
+}
+
+impl Cache {
+    /// Get the parsed format string, and whether it was in the cache
+    fn get(&mut self, format: &str) -> Result<(Rc<CompiledFormat>, bool)> {
+        if let Some(&i) = self.index.get(format) {
+            if self.newest != Some(i) {
+                self.unlink(i);
+                self.link_newest(i);
+            }
+            return Ok((self.nodes[i].compiled.clone(), true));
+        }
+        let compiled = Rc::new(CompiledFormat::new(format)?);
+        if self.capacity > 0 {
+            self.shrink_to(self.capacity - 1);
+            let format: Rc<str> = format.into();
+            self.index.insert(format.clone(), self.nodes.len());
+            self.nodes.push(Node {
+                format,
+                compiled: compiled.clone(),
+                newer: None,
+                older: None,
+            });
+            self.link_newest(self.nodes.len() - 1);
+        }
+        Ok((compiled, false))
+    }
+
+    /// Drop the least recently used entries until there are at most `len`
+    fn shrink_to(&mut self, len: usize) {
+        while self.nodes.len() > len {
+            if let Some(oldest) = self.oldest {
+                self.remove(oldest);
+            }
+        }
+    }
+
+    /// Drop the entry `nodes[i]`, moving the last one in its place
+    fn remove(&mut self, i: usize) {
+        self.unlink(i);
+        let node = self.nodes.swap_remove(i);
+        self.index.remove(&node.format);
+        if let Some(moved) = self.nodes.get(i) {
+            let (newer, older) = (moved.newer, moved.older);
+            if let Some(index) = self.index.get_mut(&moved.format) {
+                *index = i;
+            }
+            self.set_older(newer, Some(i));
+            self.set_newer(older, Some(i));
+        }
+    }
+
+    /// Take `nodes[i]` out of the order of use
+    fn unlink(&mut self, i: usize) {
+        let Node { newer, older, .. } = self.nodes[i];
+        self.set_older(newer, older);
+        self.set_newer(older, newer);
+    }
+
+    /// Put `nodes[i]`, which isn't linked, first in the order of use
+    fn link_newest(&mut self, i: usize) {
+        self.nodes[i].newer = None;
+        self.nodes[i].older = self.newest;
+        self.set_newer(self.newest, Some(i));
+        self.newest = Some(i);
+    }
+
+    /// Set the entry used before `node`, or the newest if there is no `node`
+    fn set_older(&mut self, node: Option<usize>, older: Option<usize>) {
+        match node {
+            Some(node) => self.nodes[node].older = older,
+            None => self.newest = older,
+        }
+    }
+
+    /// Set the entry used after `node`, or the oldest if there is no `node`
+    fn set_newer(&mut self, node: Option<usize>, newer: Option<usize>) {
+        match node {
+            Some(node) => self.nodes[node].newer = newer,
+            None => self.oldest = newer,
+        }
+    }
+}
+
+std::thread_local! {
+    static CACHE: RefCell<Cache> = RefCell::new(Cache {
+        index: HashMap::new(),
+        nodes: Vec::new(),
+        newest: None,
+        oldest: None,
+        capacity: DEFAULT_CAPACITY,
+        hits: 0,
+        misses: 0,
+    });
+}
+
+/// Get the parsed format string from the cache, parsing and adding it if it
+/// isn't there
+///
+/// Fails with [PrintfError::ParseError](crate::PrintfError::ParseError) if
+/// the format string is invalid.
+pub fn get(format: &str) -> Result<Rc<CompiledFormat>> {
+    CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        let (compiled, hit) = cache.get(format)?;
+        if hit {
+            cache.hits += 1;
+        } else {
+            cache.misses += 1;
+        }
+        Ok(compiled)
+    })
+}
+
+/// Parse format strings and add them to the cache of the current thread,
+/// without counting them as hits or misses
+///
+/// Stops at the first invalid format string.
+pub fn prewarm<'a>(formats: impl IntoIterator<Item = &'a str>) -> Result<()> {
+    CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        formats
+            .into_iter()
+            .try_for_each(|format| cache.get(format).map(|_| ()))
+    })
+}
+
+/// Is the format string in the cache of the current thread?
+///
+/// This doesn't count as a use of the format string.
+pub fn contains(format: &str) -> bool {
+    CACHE.with(|cache| cache.borrow().index.contains_key(format))
+}
+
+/// The statistics of the cache of the current thread
+pub fn stats() -> CacheStats {
+    CACHE.with(|cache| {
+        let cache = cache.borrow();
+        CacheStats {
+            len: cache.nodes.len(),
+            capacity: cache.capacity,
+            hits: cache.hits,
+            misses: cache.misses,
+        }
+    })
+}
+
+/// Set the maximum number of format strings in the cache of the current
+/// thread, dropping the least recently used ones if there are more. A
+/// capacity of 0 disables the cache.
+pub fn set_capacity(capacity: usize) {
+    CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        cache.capacity = capacity;
+        cache.shrink_to(capacity);
+    })
+}
+
+/// Empty the cache of the current thread and reset its statistics
+pub fn clear() {
+    CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        cache.index.clear();
+        cache.nodes.clear();
+        cache.newest = None;
+        cache.oldest = None;
+        cache.hits = 0;
+        cache.misses = 0;
+    })
+}
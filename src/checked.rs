@@ -0,0 +1,149 @@
+//! Marker traits for checking the types of arguments at compile time
+//!
+//! The `sprintf!` macro of the companion crate `sprintf-macros` requires
+//! each argument to implement the trait for its conversion, so mismatches
+//! are compile errors instead of [PrintfError::WrongType](crate::PrintfError::WrongType).
+//! They follow what [Printf] accepts at runtime, ignoring the length
+//! modifiers. To use your own [Printf] types with the macro, implement the
+//! traits for the conversions they support.
+
+#[cfg(all(feature = "alloc", target_has_atomic = "ptr"))]
+use alloc::sync::Arc;
+#[cfg(feature = "alloc")]
+use alloc::{
+    borrow::{Cow, ToOwned},
+    boxed::Box,
+    ffi::CString,
+    rc::Rc,
+    string::String,
+};
+use core::cell::Cell;
+use core::ffi::CStr;
+use core::num::{
+    NonZeroI128, NonZeroI16, NonZeroI32, NonZeroI64, NonZeroI8, NonZeroIsize, NonZeroU128,
+    NonZeroU16, NonZeroU32, NonZeroU64, NonZeroU8, NonZeroUsize, Saturating, Wrapping,
+};
+#[cfg(target_has_atomic = "ptr")]
+use core::sync::atomic::AtomicUsize;
+#[cfg(feature = "std")]
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+
+use crate::Printf;
+
+/// An argument for the integer conversions (`%d`, `%u`, `%o`, `%x`, `%X`,
+/// `%b`, `%B`) and for a width or precision given as `*`
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` can't be formatted as an integer",
+    label = "`%d`, `%x`, `*`, etc. need an integer"
+)]
+pub trait IntArg {}
+
+/// An argument for the floating point conversions (`%f`, `%e`, `%g`, `%a`
+/// and their upper case versions)
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` can't be formatted as a floating point number",
+    label = "`%f`, `%e`, `%g` and `%a` need a float"
+)]
+pub trait FloatArg {}
+
+/// An argument for `%c`
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` can't be formatted as a character",
+    label = "`%c` needs a character"
+)]
+pub trait CharArg {}
+
+/// An argument for `%s`
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` can't be formatted as a string",
+    label = "`%s` needs a string"
+)]
+pub trait StrArg {}
+
+/// An argument for `%p`
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` can't be formatted as a pointer",
+    label = "`%p` needs a pointer"
+)]
+pub trait PtrArg {}
+
+/// An argument for `%n`
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` can't store the count for `%n`",
+    label = "`%n` needs a `Cell` of an integer or an `AtomicUsize`"
+)]
+pub trait CountArg {}
+
+macro_rules! impl_arg {
+    ($trait:ident: $($t:ty),*) => {
+        $(
+            impl $trait for $t {}
+        )*
+    };
+}
+
+impl_arg!(IntArg: i8, u8, i16, u16, i32, u32, i64, u64, i128, u128, isize, usize, bool);
+impl_arg!(
+    IntArg: NonZeroI8,
+    NonZeroU8,
+    NonZeroI16,
+    NonZeroU16,
+    NonZeroI32,
+    NonZeroU32,
+    NonZeroI64,
+    NonZeroU64,
+    NonZeroI128,
+    NonZeroU128,
+    NonZeroIsize,
+    NonZeroUsize
+);
+impl_arg!(FloatArg: f32, f64);
+impl_arg!(CharArg: char, i8, u8, i16, u16, i32, u32);
+impl_arg!(CharArg: NonZeroI8, NonZeroU8, NonZeroI16, NonZeroU16, NonZeroI32, NonZeroU32);
+impl_arg!(StrArg: str, CStr, [u8]);
+#[cfg(feature = "alloc")]
+impl_arg!(StrArg: [u16], [u32], String, CString);
+#[cfg(feature = "std")]
+impl_arg!(StrArg: OsStr, OsString, Path, PathBuf);
+impl_arg!(PtrArg: i8, u8, i16, u16, i32, u32, i64, u64, i128, u128, isize, usize);
+impl_arg!(CountArg: Cell<i8>, Cell<u8>, Cell<i16>, Cell<u16>, Cell<i32>, Cell<u32>);
+impl_arg!(CountArg: Cell<i64>, Cell<u64>, Cell<isize>, Cell<usize>);
+#[cfg(target_has_atomic = "ptr")]
+impl_arg!(CountArg: AtomicUsize);
+
+/// Pointers are also formatted as integers
+impl<T> IntArg for *const T {}
+impl<T> IntArg for *mut T {}
+impl<T> PtrArg for *const T {}
+impl<T> PtrArg for *mut T {}
+
+/// References, smart pointers and wrappers take the same conversions as the
+/// values they hold, and the type of a `dyn Printf` is only known at runtime
+macro_rules! impl_arg_forwarding {
+    ($($trait:ident),*) => {
+        $(
+            impl<T: $trait + ?Sized> $trait for &T {}
+            impl<T: $trait + ?Sized> $trait for &mut T {}
+            #[cfg(feature = "alloc")]
+            impl<T: $trait + ?Sized> $trait for Box<T> {}
+            #[cfg(feature = "alloc")]
+            impl<T: $trait + ?Sized> $trait for Rc<T> {}
+            #[cfg(all(feature = "alloc", target_has_atomic = "ptr"))]
+            impl<T: $trait + ?Sized> $trait for Arc<T> {}
+            #[cfg(feature = "alloc")]
+            impl<T: $trait + ToOwned + ?Sized> $trait for Cow<'_, T> {}
+            impl<T: $trait> $trait for Wrapping<T> {}
+            impl<T: $trait> $trait for Saturating<T> {}
+            impl $trait for dyn Printf + '_ {}
+        )*
+    };
+}
+
+impl_arg_forwarding!(IntArg, FloatArg, CharArg, StrArg, PtrArg, CountArg);
+
+/// `None` can only be formatted with `%s` and `%p`
+impl<T: StrArg> StrArg for Option<T> {}
+impl<T: PtrArg> PtrArg for Option<T> {}
@@ -0,0 +1,270 @@
+//! Owned, parsed format strings
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::{fmt, mem, str};
+
+use crate::parser::{
+    count_args, ConversionSpecifier, ConversionType, FormatElements, Located, Position,
+};
+use crate::{
+    check_arg_count, check_count_forbidden, numeric_param_arg, write_checked, Args, FormatElement,
+    FormatOptions, Printf, Result, Sink,
+};
+
+/// A parsed format string that owns its text
+///
+/// Unlike the [FormatElement]s returned by
+/// [parse_format_string](crate::parser::parse_format_string), it doesn't
+/// borrow the format string, so it can be stored and reused for as long as
+/// needed. The verbatim text is kept in the same allocation as the
+/// conversions.
+///
+///     use sprintf::{CompiledFormat, Printf};
+///     let format = CompiledFormat::new("%s has %d items").unwrap();
+///     assert_eq!(format.arg_count(), 2);
+///     let s = format.vsprintf(&[&"cart" as &dyn Printf, &3]).unwrap();
+///     assert_eq!(s, "cart has 3 items");
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompiledFormat {
+    elements: Box<[CompiledElement]>,
+    arg_count: usize,
+}
+
+/// The most verbatim text stored in one element: as much as fits in the
+/// space a conversion takes anyway
+const CHUNK_LEN: usize = mem::size_of::<(ConversionSpecifier, usize)>() - 1;
+
+// the length of a chunk is stored in a `u8`
+const _: () = assert!(CHUNK_LEN <= u8::MAX as usize);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CompiledElement {
+    /// Verbatim text, `bytes[..len]`. Longer text is split into several
+    /// elements, at char boundaries.
+    Verbatim { len: u8, bytes: [u8; CHUNK_LEN] },
+    /// A conversion specifier, and its length in the format string
+    Format(ConversionSpecifier, usize),
+}
+
+impl CompiledElement {
+    /// Split verbatim text into elements
+    fn verbatim(mut text: &str, elements: &mut Vec<CompiledElement>) {
+        while !text.is_empty() {
+            let mut len = text.len().min(CHUNK_LEN);
+            while !text.is_char_boundary(len) {
+                len -= 1;
+            }
+            let mut bytes = [0; CHUNK_LEN];
+            bytes[..len].copy_from_slice(&text.as_bytes()[..len]);
+            elements.push(CompiledElement::Verbatim {
+                len: len as u8,
+                bytes,
+            });
+            text = &text[len..];
+        }
+    }
+
+    /// The length of the element in the format string
+    fn len(&self) -> usize {
+        match *self {
+            CompiledElement::Verbatim { len, .. } => len.into(),
+            CompiledElement::Format(_, len) => len,
+        }
+    }
+
+    /// The element as parsed
+    fn element(&self) -> FormatElement<'_> {
+        match self {
+            // only split at char boundaries, so always valid
+            CompiledElement::Verbatim { len, bytes } => {
+                FormatElement::Verbatim(str::from_utf8(&bytes[..usize::from(*len)]).unwrap_or(""))
+            }
+            CompiledElement::Format(spec, _) => FormatElement::Format(*spec),
+        }
+    }
+}
+
+/// Shows the verbatim text as a string
+impl fmt::Debug for CompiledElement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.element() {
+            FormatElement::Verbatim(s) => f.debug_tuple("Verbatim").field(&s).finish(),
+            FormatElement::Format(spec) => f.debug_tuple("Format").field(&spec).finish(),
+        }
+    }
+}
+
+/// A use of an argument by a conversion of a [CompiledFormat]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArgUse {
+    /// The (zero-based) index of the argument
+    pub index: usize,
+    /// What the argument is used for
+    pub role: ArgRole,
+    /// The conversion using the argument
+    pub spec: ConversionSpecifier,
+}
+
+/// What a conversion uses an argument for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgRole {
+    /// The field width, given by `*`
+    Width,
+    /// The precision, given by `.*`
+    Precision,
+    /// The value to format
+    Value,
+}
+
+impl CompiledFormat {
+    /// Parse a format string
+    ///
+    /// Fails with [PrintfError::ParseError](crate::PrintfError::ParseError)
+    /// if it is invalid, including if it mixes numbered and unnumbered
+    /// conversions.
+    pub fn new(format: &str) -> Result<Self> {
+        let arg_count = count_args(FormatElements::new(format).located())?;
+        let mut elements = Vec::new();
+        let mut iter = FormatElements::new(format);
+        let mut start = 0;
+        while let Some(elem) = iter.next() {
+            match elem? {
+                FormatElement::Verbatim(s) => CompiledElement::verbatim(s, &mut elements),
+                FormatElement::Format(spec) => {
+                    elements.push(CompiledElement::Format(spec, iter.offset() - start))
+                }
+            }
+            start = iter.offset();
+        }
+        Ok(CompiledFormat {
+            elements: elements.into_boxed_slice(),
+            arg_count,
+        })
+    }
+
+    /// The number of arguments the format string takes
+    pub fn arg_count(&self) -> usize {
+        self.arg_count
+    }
+
+    /// The parts of the format string
+    ///
+    /// Long verbatim text may be split into several parts.
+    pub fn elements(&self) -> impl Iterator<Item = FormatElement<'_>> + Clone + '_ {
+        self.elements.iter().map(CompiledElement::element)
+    }
+
+    /// The parts of the format string with their positions in the original
+    fn located(&self) -> impl Iterator<Item = Result<Located<'_>>> + Clone + '_ {
+        self.elements()
+            .zip(self.elements.iter())
+            .scan(0, |offset, (elem, compiled)| {
+                let len = compiled.len();
+                let located = Located {
+                    elem,
+                    position: Position::Known {
+                        offset: *offset,
+                        len,
+                    },
+                };
+                *offset += len;
+                Some(Ok(located))
+            })
+    }
+
+    /// The uses of the arguments, in the order they are taken: for each
+    /// conversion, its width, its precision, and its value
+    ///
+    ///     use sprintf::{ArgRole, CompiledFormat};
+    ///     let format = CompiledFormat::new("%1$-*2$s|%2$d").unwrap();
+    ///     let plan: Vec<_> = format.arg_uses().map(|u| (u.index, u.role)).collect();
+    ///     assert_eq!(plan, [(1, ArgRole::Width), (0, ArgRole::Value), (1, ArgRole::Value)]);
+    ///
+    /// An argument may be used more than once with numbered arguments
+    /// (`%1$d`).
+    pub fn arg_uses(&self) -> impl Iterator<Item = ArgUse> + '_ {
+        let mut next_arg = 0;
+        let mut index = move |arg_number: Option<usize>| match arg_number {
+            Some(n) => n - 1,
+            None => {
+                next_arg += 1;
+                next_arg - 1
+            }
+        };
+        self.elements
+            .iter()
+            .filter_map(|elem| match *elem {
+                CompiledElement::Format(spec, _)
+                    if spec.conversion_type != ConversionType::PercentSign =>
+                {
+                    Some(spec)
+                }
+                _ => None,
+            })
+            .flat_map(move |spec| {
+                let mut param_use = |param, role| {
+                    numeric_param_arg(param).map(|arg_number| ArgUse {
+                        index: index(arg_number),
+                        role,
+                        spec,
+                    })
+                };
+                let width = param_use(spec.width, ArgRole::Width);
+                let precision = param_use(spec.precision, ArgRole::Precision);
+                let value = ArgUse {
+                    index: index(spec.arg_number),
+                    role: ArgRole::Value,
+                    spec,
+                };
+                width.into_iter().chain(precision).chain(Some(value))
+            })
+    }
+
+    /// Format the arguments. Like [vsprintfp](crate::vsprintfp)
+    pub fn vsprintf(&self, args: &[&dyn Printf]) -> Result<String> {
+        self.vsprintf_with(&FormatOptions::default(), args)
+    }
+
+    /// Format the arguments with the given options
+    pub fn vsprintf_with(&self, options: &FormatOptions, args: &[&dyn Printf]) -> Result<String> {
+        let mut res = String::new();
+        self.vwritef_with(options, &mut res, args)?;
+        Ok(res)
+    }
+
+    /// Format the arguments, writing the result to a [fmt::Write]. Like
+    /// [vwritefp](crate::vwritefp)
+    pub fn vwritef<W: fmt::Write>(&self, out: &mut W, args: &[&dyn Printf]) -> Result<()> {
+        self.vwritef_with(&FormatOptions::default(), out, args)
+    }
+
+    /// Format the arguments with the given options, writing the result to a
+    /// [fmt::Write]
+    ///
+    /// The format string was checked by [new](Self::new), so only the
+    /// arguments are.
+    pub fn vwritef_with<W: fmt::Write>(
+        &self,
+        options: &FormatOptions,
+        out: &mut W,
+        args: &[&dyn Printf],
+    ) -> Result<()> {
+        check_arg_count(self.located(), args, self.arg_count)?;
+        if options.forbid_count {
+            check_count_forbidden(self.located())?;
+        }
+        write_checked(
+            Sink::Text(out),
+            self.located(),
+            &mut Args::new(args),
+            options,
+        )
+    }
+}
+
+/// Reproduces the format string
+impl fmt::Display for CompiledFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.elements().try_for_each(|elem| elem.fmt(f))
+    }
+}
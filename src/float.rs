@@ -0,0 +1,448 @@
+//! Exact floating point to decimal conversion
+//!
+//! Every finite `f64` is a binary fraction `m * 2^e`, which has a finite
+//! decimal expansion. We compute that expansion exactly using a small
+//! fixed-size big integer, and then round it in decimal, so the output for
+//! any precision matches glibc (which does the same).
+
+use core::fmt;
+
+use crate::parser::ConversionType;
+
+/// Number of 32-bit limbs needed for `2^53 * 5^1074`, the largest number
+/// we need to represent (and a bit of headroom)
+const BIGINT_LIMBS: usize = 84;
+
+/// Maximum number of significant decimal digits of an `f64` (767, rounded up)
+const MAX_DIGITS: usize = 780;
+
+/// A fixed-size unsigned big integer (little-endian limbs)
+struct BigInt {
+    limbs: [u32; BIGINT_LIMBS],
+    len: usize,
+}
+
+impl BigInt {
+    fn from_u64(n: u64) -> Self {
+        let mut res = BigInt {
+            limbs: [0; BIGINT_LIMBS],
+            len: 2,
+        };
+        res.limbs[0] = n as u32;
+        res.limbs[1] = (n >> 32) as u32;
+        res.trim();
+        res
+    }
+
+    fn trim(&mut self) {
+        while self.len > 0 && self.limbs[self.len - 1] == 0 {
+            self.len -= 1;
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        self.len == 0
+    }
+
+    fn mul_small(&mut self, factor: u32) {
+        let mut carry = 0_u64;
+        for limb in &mut self.limbs[..self.len] {
+            let prod = *limb as u64 * factor as u64 + carry;
+            *limb = prod as u32;
+            carry = prod >> 32;
+        }
+        if carry != 0 {
+            self.limbs[self.len] = carry as u32;
+            self.len += 1;
+        }
+    }
+
+    fn mul_pow2(&mut self, mut exp: u32) {
+        while exp > 0 {
+            let step = exp.min(31);
+            self.mul_small(1 << step);
+            exp -= step;
+        }
+    }
+
+    fn mul_pow5(&mut self, mut exp: u32) {
+        // 5^13 is the largest power of 5 that fits in a u32
+        while exp > 0 {
+            let step = exp.min(13);
+            self.mul_small(5_u32.pow(step));
+            exp -= step;
+        }
+    }
+
+    /// Divide by `divisor` in place, returning the remainder
+    fn divrem_small(&mut self, divisor: u32) -> u32 {
+        let mut rem = 0_u64;
+        for limb in self.limbs[..self.len].iter_mut().rev() {
+            let cur = (rem << 32) | *limb as u64;
+            *limb = (cur / divisor as u64) as u32;
+            rem = cur % divisor as u64;
+        }
+        self.trim();
+        rem as u32
+    }
+}
+
+/// The exact decimal representation of a non-negative number:
+/// `0.d0 d1 d2 ... * 10^(exponent + 1)`, i.e. `d0` has the weight
+/// `10^exponent`.
+///
+/// Trailing zeros are not stored; zero has no digits.
+pub(crate) struct Decimal {
+    digits: [u8; MAX_DIGITS],
+    len: usize,
+    exponent: i32,
+}
+
+impl Decimal {
+    /// Get the exact decimal representation of the finite number `abs`
+    pub(crate) fn from_f64(abs: f64) -> Self {
+        let bits = abs.to_bits();
+        let biased_exp = ((bits >> 52) & 0x7ff) as i32;
+        let frac = bits & ((1 << 52) - 1);
+        let (mant, exp2) = if biased_exp == 0 {
+            (frac, -1074)
+        } else {
+            (frac | (1 << 52), biased_exp - 1075)
+        };
+
+        let mut res = Decimal {
+            digits: [0; MAX_DIGITS],
+            len: 0,
+            exponent: 0,
+        };
+        if mant == 0 {
+            return res;
+        }
+
+        // abs = mant * 2^exp2 = (mant * 5^-exp2) * 10^exp2 if exp2 < 0
+        let mut n = BigInt::from_u64(mant);
+        let exp10 = if exp2 >= 0 {
+            n.mul_pow2(exp2 as u32);
+            0
+        } else {
+            n.mul_pow5((-exp2) as u32);
+            exp2
+        };
+
+        // Convert the big integer to decimal, 9 digits at a time, from the
+        // least significant end. The digits are collected in reverse.
+        let mut n_digits = 0;
+        while !n.is_zero() {
+            let mut chunk = n.divrem_small(1_000_000_000);
+            for _ in 0..9 {
+                res.digits[n_digits] = (chunk % 10) as u8;
+                chunk /= 10;
+                n_digits += 1;
+            }
+        }
+        // strip leading zeros (at the end of the reversed digits)
+        while res.digits[n_digits - 1] == 0 {
+            n_digits -= 1;
+        }
+        // strip trailing zeros (at the start of the reversed digits)
+        let trailing_zeros = res.digits[..n_digits]
+            .iter()
+            .take_while(|&&d| d == 0)
+            .count();
+        res.digits.copy_within(trailing_zeros..n_digits, 0);
+        res.len = n_digits - trailing_zeros;
+        res.digits[..res.len].reverse();
+        res.exponent = exp10 + n_digits as i32 - 1;
+        res
+    }
+
+    fn is_zero(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The digit with weight `10^pos`
+    ///
+    /// `pos` is an `i64` since it is computed from a precision, which may be
+    /// as large as `i32::MAX`.
+    fn digit_at(&self, pos: i64) -> u8 {
+        let idx = i64::from(self.exponent) - pos;
+        if idx >= 0 && (idx as usize) < self.len {
+            self.digits[idx as usize]
+        } else {
+            0
+        }
+    }
+
+    /// Round to `n` significant digits (round half to even)
+    ///
+    /// `n` may be zero or negative, in which case the number is rounded to
+    /// a multiple of `10^(exponent + 1 - n)`.
+    fn round_to(&mut self, n: i32) {
+        if n < 0 || self.is_zero() {
+            // the value is smaller than half a unit in the last place
+            self.len = 0;
+            self.exponent = 0;
+            return;
+        }
+        let n = n as usize;
+        if n >= self.len {
+            return; // exact
+        }
+        let next = self.digits[n];
+        let last_odd = n > 0 && self.digits[n - 1] % 2 == 1;
+        let round_up = next > 5 || (next == 5 && (self.len > n + 1 || last_odd));
+        self.len = n;
+        if round_up {
+            // increment, propagating the carry
+            let mut i = n;
+            loop {
+                if i == 0 {
+                    // all nines (or nothing): becomes 1 with a higher exponent
+                    self.digits[0] = 1;
+                    self.len = 1;
+                    self.exponent += 1;
+                    break;
+                }
+                i -= 1;
+                if self.digits[i] == 9 {
+                    self.len = i; // trailing zero, drop it
+                } else {
+                    self.digits[i] += 1;
+                    break;
+                }
+            }
+        }
+        // drop trailing zeros
+        while self.len > 0 && self.digits[self.len - 1] == 0 {
+            self.len -= 1;
+        }
+        if self.is_zero() {
+            self.exponent = 0;
+        }
+    }
+
+    /// Write the number in fixed-point notation with `precision` decimals
+    fn write_fixed(&self, out: &mut dyn fmt::Write, precision: i32, alt_form: bool) -> fmt::Result {
+        let int_digits = if self.is_zero() { 0 } else { self.exponent };
+        for pos in (0..=int_digits.max(0)).rev() {
+            out.write_char((b'0' + self.digit_at(pos.into())) as char)?;
+        }
+        if precision > 0 || alt_form {
+            out.write_char('.')?;
+        }
+        for pos in 1..=precision {
+            out.write_char((b'0' + self.digit_at(-i64::from(pos))) as char)?;
+        }
+        Ok(())
+    }
+
+    /// Write the number in scientific notation with `precision` decimals
+    fn write_scientific(
+        &self,
+        out: &mut dyn fmt::Write,
+        precision: i32,
+        alt_form: bool,
+        exp_symb: char,
+    ) -> fmt::Result {
+        let exponent = if self.is_zero() { 0 } else { self.exponent };
+        out.write_char((b'0' + self.digit_at(exponent.into())) as char)?;
+        if precision > 0 || alt_form {
+            out.write_char('.')?;
+        }
+        for pos in 1..=precision {
+            out.write_char((b'0' + self.digit_at(i64::from(exponent) - i64::from(pos))) as char)?;
+        }
+        let sign = if exponent < 0 { '-' } else { '+' };
+        write!(out, "{}{}{:02}", exp_symb, sign, exponent.unsigned_abs())
+    }
+}
+
+/// A finite, non-negative number prepared for one of the decimal conversions
+/// (`%f`, `%e` or `%g`), rounded to the required precision
+pub(crate) struct DecimalFloat {
+    dec: Decimal,
+    scientific: bool,
+    /// number of digits after the decimal point
+    precision: i32,
+    alt_form: bool,
+    exp_symb: char,
+}
+
+impl DecimalFloat {
+    pub(crate) fn new(
+        abs: f64,
+        conversion_type: ConversionType,
+        precision: i32,
+        alt_form: bool,
+    ) -> Self {
+        // default precision
+        let precision = if precision < 0 { 6 } else { precision };
+        let mut dec = Decimal::from_f64(abs);
+        let exp_symb = match conversion_type {
+            ConversionType::SciFloatUpper | ConversionType::CompactFloatUpper => 'E',
+            _ => 'e',
+        };
+        match conversion_type {
+            ConversionType::SciFloatLower | ConversionType::SciFloatUpper => {
+                // the precision may be as large as `i32::MAX`, and a number
+                // can't have that many digits anyway
+                dec.round_to(precision.saturating_add(1));
+                DecimalFloat {
+                    dec,
+                    scientific: true,
+                    precision,
+                    alt_form,
+                    exp_symb,
+                }
+            }
+            ConversionType::CompactFloatLower | ConversionType::CompactFloatUpper => {
+                // precision specifies the number of significant digits
+                let p = precision.max(1);
+                let exact_exponent = dec.exponent;
+                dec.round_to(p);
+                let exponent = if dec.is_zero() { 0 } else { dec.exponent };
+                let scientific = exponent < -4 || exponent >= p;
+                let mut precision = if !scientific {
+                    (p - 1).saturating_sub(exponent)
+                } else if exact_exponent == p - 1 && exponent == p {
+                    // glibc quirk: if rounding carries a number that would
+                    // have been printed without decimals over into scientific
+                    // notation, no decimals are printed (visible with `#`)
+                    0
+                } else {
+                    p - 1
+                };
+                if !alt_form {
+                    // strip trailing zeros
+                    let last_digit = exponent - (dec.len as i32 - 1).max(0);
+                    let needed = if scientific {
+                        exponent - last_digit
+                    } else {
+                        -last_digit
+                    };
+                    precision = precision.min(needed.max(0));
+                }
+                DecimalFloat {
+                    dec,
+                    scientific,
+                    precision,
+                    alt_form,
+                    exp_symb,
+                }
+            }
+            _ => {
+                // %f / %F
+                let n = if dec.is_zero() {
+                    0
+                } else {
+                    (dec.exponent + 1).saturating_add(precision)
+                };
+                dec.round_to(n);
+                DecimalFloat {
+                    dec,
+                    scientific: false,
+                    precision,
+                    alt_form,
+                    exp_symb,
+                }
+            }
+        }
+    }
+
+    /// Write the number, without sign
+    pub(crate) fn write(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        if self.scientific {
+            self.dec
+                .write_scientific(out, self.precision, self.alt_form, self.exp_symb)
+        } else {
+            self.dec.write_fixed(out, self.precision, self.alt_form)
+        }
+    }
+}
+
+/// A finite, non-negative number prepared for the hexadecimal float
+/// conversion (`%a`), rounded to the required precision
+pub(crate) struct HexFloat {
+    /// leading digit and mantissa, as a 53-bit fixed point number
+    full: u64,
+    exponent: i32,
+    n_digits: i32,
+    alt_form: bool,
+    upper: bool,
+}
+
+impl HexFloat {
+    const MANT_BITS: u32 = 52;
+    const MANT_DIGITS: i32 = (Self::MANT_BITS / 4) as i32;
+
+    /// A negative `precision` means "as many digits as needed".
+    pub(crate) fn new(abs: f64, precision: i32, alt_form: bool, upper: bool) -> Self {
+        const MANT_BITS: u32 = HexFloat::MANT_BITS;
+        const MANT_DIGITS: i32 = HexFloat::MANT_DIGITS;
+
+        let bits = abs.to_bits();
+        let biased_exp = (bits >> MANT_BITS) as i32;
+        let mant = bits & ((1 << MANT_BITS) - 1);
+        // Like glibc, print subnormals with a leading 0 instead of normalising them
+        let (lead, exponent) = match (biased_exp, mant) {
+            (0, 0) => (0, 0),
+            (0, _) => (0, -1022),
+            _ => (1, biased_exp - 1023),
+        };
+        let mut full = (lead << MANT_BITS) | mant;
+
+        let n_digits = if precision < 0 {
+            // minimal number of digits to represent the value exactly
+            let mut n = MANT_DIGITS;
+            while n > 0 && (full >> ((MANT_DIGITS - n) * 4)) & 0xf == 0 {
+                n -= 1;
+            }
+            n
+        } else if precision < MANT_DIGITS {
+            // round to nearest, ties to even
+            let shift = ((MANT_DIGITS - precision) * 4) as u32;
+            let rem = full & ((1 << shift) - 1);
+            let half = 1 << (shift - 1);
+            full >>= shift;
+            if rem > half || (rem == half && full & 1 == 1) {
+                full += 1;
+            }
+            full <<= shift;
+            precision
+        } else {
+            precision
+        };
+
+        HexFloat {
+            full,
+            exponent,
+            n_digits,
+            alt_form,
+            upper,
+        }
+    }
+
+    /// Write the number, without sign and `0x` prefix
+    pub(crate) fn write(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        let digits: &[u8] = if self.upper {
+            b"0123456789ABCDEF"
+        } else {
+            b"0123456789abcdef"
+        };
+        // the leading digit may have been rounded up to 2
+        out.write_char(digits[(self.full >> Self::MANT_BITS) as usize] as char)?;
+        if self.n_digits > 0 || self.alt_form {
+            out.write_char('.')?;
+        }
+        for i in 0..self.n_digits {
+            let digit = if i < Self::MANT_DIGITS {
+                (self.full >> ((Self::MANT_DIGITS - 1 - i) * 4)) & 0xf
+            } else {
+                0
+            };
+            out.write_char(digits[digit as usize] as char)?;
+        }
+        let exp_symb = if self.upper { 'P' } else { 'p' };
+        write!(out, "{}{:+}", exp_symb, self.exponent)
+    }
+}
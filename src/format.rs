@@ -1,9 +1,39 @@
-use std::convert::{TryFrom, TryInto};
-use std::ffi::{CStr, CString};
+#[cfg(all(feature = "alloc", target_has_atomic = "ptr"))]
+use alloc::sync::Arc;
+#[cfg(feature = "alloc")]
+use alloc::{
+    borrow::{Cow, ToOwned},
+    boxed::Box,
+    ffi::CString,
+    rc::Rc,
+    string::String,
+};
+use core::cell::Cell;
+use core::convert::{TryFrom, TryInto};
+use core::ffi::{c_int, c_long, c_longlong, c_schar, c_short, CStr};
+use core::num::{
+    NonZeroI128, NonZeroI16, NonZeroI32, NonZeroI64, NonZeroI8, NonZeroIsize, NonZeroU128,
+    NonZeroU16, NonZeroU32, NonZeroU64, NonZeroU8, NonZeroUsize, Saturating, Wrapping,
+};
+#[cfg(target_has_atomic = "ptr")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{fmt, str};
+#[cfg(feature = "std")]
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+
+#[cfg(feature = "unicode")]
+use unicode_segmentation::UnicodeSegmentation;
+#[cfg(feature = "unicode")]
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 use crate::{
-    parser::{ConversionSpecifier, ConversionType, NumericParam},
-    PrintfError, Result,
+    float::{DecimalFloat, HexFloat},
+    locale::{LeadingDigits, Locale, Localizer},
+    parser::{ConversionSpecifier, ConversionType, LengthModifier, NumericParam},
+    FormatOptions, PrintfError, Result, TextWidth,
 };
 
 /// Trait for types that can be formatted using printf strings
@@ -12,597 +42,1570 @@ use crate::{
 /// anything else.
 pub trait Printf {
     /// Format `self` based on the conversion configured in `spec`.
-    fn format(&self, spec: &ConversionSpecifier) -> Result<String>;
-    /// Get `self` as an integer for use as a field width, if possible.
+    #[cfg(feature = "alloc")]
+    fn format(&self, spec: &ConversionSpecifier) -> Result<String> {
+        let mut s = String::new();
+        self.write_to(spec, &mut s)?;
+        Ok(s)
+    }
+    /// Write `self` to `out`, formatted based on the conversion configured
+    /// in `spec`.
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()>;
+    /// Write `self` to `out` like [write_to](Printf::write_to), taking the
+    /// [FormatOptions] into account: the locale for numbers, and how to
+    /// measure text.
+    ///
+    /// By default, the options are ignored.
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
+        let _ = options;
+        self.write_to(spec, out)
+    }
+    /// Get `self` as an integer for use as a field width or precision, if
+    /// possible.
     fn as_int(&self) -> Option<i32>;
+    /// Get the bits of `self` (in two's complement) if it is an integer, so
+    /// it can be converted to the C type given by the length modifier.
+    fn as_int_bits(&self) -> Option<u128> {
+        None
+    }
+    /// Get the C type that corresponds to the type of `self`, if there is
+    /// one, for checking arguments in strict mode.
+    fn c_type(&self) -> Option<CType> {
+        None
+    }
+    /// Store the number of bytes written so far, for `%n`.
+    fn store_count(&self, count: usize) -> Result<()> {
+        let _ = count;
+        Err(PrintfError::WrongType(None))
+    }
+    /// Get `self` as a byte string if it is one, so the byte-oriented
+    /// functions like [vsprintf_bytes](crate::vsprintf_bytes) can write it
+    /// for `%s` as it is, even if it isn't valid UTF-8.
+    fn as_byte_str(&self) -> Option<&[u8]> {
+        None
+    }
 }
 
-impl Printf for u64 {
-    fn format(&self, spec: &ConversionSpecifier) -> Result<String> {
-        let mut base = 10;
-        let mut digits: Vec<char> = "0123456789".chars().collect();
-        let mut alt_prefix = "";
-        match spec.conversion_type {
-            ConversionType::DecInt => {}
-            ConversionType::HexIntLower => {
-                base = 16;
-                digits = "0123456789abcdef".chars().collect();
-                alt_prefix = "0x";
-            }
-            ConversionType::HexIntUpper => {
-                base = 16;
-                digits = "0123456789ABCDEF".chars().collect();
-                alt_prefix = "0X";
-            }
-            ConversionType::OctInt => {
-                base = 8;
-                digits = "01234567".chars().collect();
-                alt_prefix = "0";
-            }
-            _ => {
-                return Err(PrintfError::WrongType);
-            }
+/// The C type of an argument, as far as printf is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CType {
+    /// An integer type with the given number of bits, signed or unsigned
+    Int(u32),
+    /// A character
+    Char,
+    /// `double` (`float` is promoted to `double` in printf)
+    Double,
+    /// A string (`char *`)
+    String,
+    /// A wide string (`wchar_t *`) with code units of the given number of
+    /// bits
+    WideString(u32),
+    /// A pointer (`void *`)
+    Pointer,
+    /// A pointer to an integer type with the given number of bits, for `%n`
+    IntPtr(u32),
+    /// A null pointer (`None`), which is accepted for strings and `%p`
+    Null,
+}
+
+/// A [fmt::Write] that only counts the bytes written to it
+struct ByteCounter(usize);
+
+impl fmt::Write for ByteCounter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+/// A [fmt::Write] that counts the bytes written to it, and keeps them if
+/// they fit into its buffer
+struct Measure {
+    buf: [u8; 128],
+    len: usize,
+}
+
+impl Measure {
+    /// What was written, unless it didn't fit
+    fn contents(&self) -> Option<&str> {
+        self.buf
+            .get(..self.len)
+            .and_then(|bytes| core::str::from_utf8(bytes).ok())
+    }
+}
+
+impl fmt::Write for Measure {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(dest) = self.buf.get_mut(self.len..self.len + s.len()) {
+            dest.copy_from_slice(s.as_bytes());
         }
-        let prefix = if spec.alt_form {
-            alt_prefix.to_owned()
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+/// Get the field width (must be a literal at this point)
+fn width(spec: &ConversionSpecifier) -> Result<usize> {
+    match spec.width {
+        NumericParam::Literal(w) => Ok(w.try_into().unwrap_or_default()),
+        _ => Err(PrintfError::Unknown), // should not happen at this point!!
+    }
+}
+
+/// Get the precision (must be a literal at this point)
+fn precision(spec: &ConversionSpecifier) -> Result<i32> {
+    match spec.precision {
+        NumericParam::Literal(p) => Ok(p),
+        _ => Err(PrintfError::Unknown), // should not happen at this point!!
+    }
+}
+
+fn write_repeated(out: &mut dyn fmt::Write, c: char, n: usize) -> fmt::Result {
+    for _ in 0..n {
+        out.write_char(c)?;
+    }
+    Ok(())
+}
+
+/// Write the `prefix` parts followed by whatever `body` writes to `out`,
+/// padded to the field width.
+///
+/// If `zero_pad` is set, zeros are inserted between the prefix and the body
+/// instead of spaces before the prefix (unless left-adjusted).
+fn write_padded(
+    out: &mut dyn fmt::Write,
+    spec: &ConversionSpecifier,
+    prefix: &[&str],
+    zero_pad: bool,
+    body: impl Fn(&mut dyn fmt::Write) -> fmt::Result,
+) -> Result<()> {
+    let prefix_len: usize = prefix.iter().map(|p| p.len()).sum();
+    if width(spec)? <= prefix_len {
+        // no padding, so there is no need to measure the body, which may
+        // be huge (`%.2147483647f`)
+        prefix.iter().try_for_each(|p| out.write_str(p))?;
+        return Ok(body(out)?);
+    }
+    let mut measure = Measure {
+        buf: [0; 128],
+        len: 0,
+    };
+    body(&mut measure)?;
+    match measure.contents() {
+        Some(text) => write_padded_to_len(out, spec, prefix, zero_pad, text.len(), |w| {
+            w.write_str(text)
+        }),
+        // too long to keep, so format it again
+        None => write_padded_to_len(out, spec, prefix, zero_pad, measure.len, body),
+    }
+}
+
+/// Like [write_padded], but counting the length of what `body` writes as
+/// `len` instead of measuring it in bytes
+fn write_padded_to_len(
+    out: &mut dyn fmt::Write,
+    spec: &ConversionSpecifier,
+    prefix: &[&str],
+    zero_pad: bool,
+    len: usize,
+    body: impl Fn(&mut dyn fmt::Write) -> fmt::Result,
+) -> Result<()> {
+    let len = len + prefix.iter().map(|p| p.len()).sum::<usize>();
+    let padding = width(spec)?.saturating_sub(len);
+    let write_prefix = |out: &mut dyn fmt::Write| prefix.iter().try_for_each(|p| out.write_str(p));
+
+    if spec.left_adj {
+        write_prefix(out)?;
+        body(out)?;
+        write_repeated(out, ' ', padding)?;
+    } else if zero_pad {
+        write_prefix(out)?;
+        write_repeated(out, '0', padding)?;
+        body(out)?;
+    } else {
+        write_repeated(out, ' ', padding)?;
+        write_prefix(out)?;
+        body(out)?;
+    }
+    Ok(())
+}
+
+/// Write a byte string for `%s`, or a byte for `%c`, with `write_bytes`,
+/// counting the precision and the width in bytes like C
+pub(crate) fn write_byte_str(
+    spec: &ConversionSpecifier,
+    bytes: &[u8],
+    mut write_bytes: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<()> {
+    let precision = match spec.conversion_type {
+        // the precision has no effect on `%c`
+        ConversionType::Char => usize::MAX,
+        _ => precision(spec)?.try_into().unwrap_or(usize::MAX),
+    };
+    let content = &bytes[..precision.min(bytes.len())];
+    let padding = width(spec)?.saturating_sub(content.len());
+    if !spec.left_adj {
+        (0..padding).try_for_each(|_| write_bytes(b" "))?;
+    }
+    write_bytes(content)?;
+    if spec.left_adj {
+        (0..padding).try_for_each(|_| write_bytes(b" "))?;
+    }
+    Ok(())
+}
+
+/// Write an integer given as its absolute value and sign
+fn write_int(
+    out: &mut dyn fmt::Write,
+    spec: &ConversionSpecifier,
+    locale: &Locale,
+    abs: u128,
+    negative: bool,
+) -> Result<()> {
+    if spec.conversion_type == ConversionType::Pointer && abs == 0 {
+        // glibc prints null pointers as "(nil)", ignoring zero padding and
+        // precision
+        return write_padded(out, spec, &[], false, |w| w.write_str("(nil)"));
+    }
+    let (base, digits, alt_prefix): (u128, &[u8], &str) = match spec.conversion_type {
+        ConversionType::DecInt | ConversionType::UnsignedInt => (10, b"0123456789", ""),
+        ConversionType::HexIntLower => (16, b"0123456789abcdef", "0x"),
+        ConversionType::HexIntUpper => (16, b"0123456789ABCDEF", "0X"),
+        ConversionType::BinIntLower => (2, b"01", "0b"),
+        ConversionType::BinIntUpper => (2, b"01", "0B"),
+        ConversionType::OctInt => (8, b"01234567", ""),
+        ConversionType::Pointer => (16, b"0123456789abcdef", "0x"),
+        _ => {
+            return Err(PrintfError::WrongType(None));
+        }
+    };
+    let sign = if negative {
+        "-"
+    } else if !matches!(
+        spec.conversion_type,
+        ConversionType::DecInt | ConversionType::Pointer
+    ) {
+        // unsigned conversion
+        ""
+    } else if spec.force_sign {
+        "+"
+    } else if spec.space_sign {
+        " "
+    } else {
+        ""
+    };
+    // The alternative form only gets a prefix if the number isn't 0.
+    // Pointers always get one.
+    let alt_prefix =
+        if (spec.alt_form || spec.conversion_type == ConversionType::Pointer) && abs != 0 {
+            alt_prefix
         } else {
-            String::new()
+            ""
+        };
+
+    // Build the actual number (from the back)
+    let mut buf = [0_u8; 128];
+    let mut start = buf.len();
+    let mut n = abs;
+    while n != 0 {
+        start -= 1;
+        buf[start] = digits[(n % base) as usize];
+        n /= base;
+    }
+    let number = core::str::from_utf8(&buf[start..]).map_err(|_| PrintfError::Unknown)?;
+    let write_number = |w: &mut dyn fmt::Write| {
+        let mut localizer = Localizer {
+            out: w,
+            locale,
+            digits: spec.locale_digits
+                && matches!(
+                    spec.conversion_type,
+                    ConversionType::DecInt | ConversionType::UnsignedInt
+                ),
+            int_digits: if spec.thousands_grouping {
+                number.len()
+            } else {
+                0
+            },
         };
+        fmt::Write::write_str(&mut localizer, number)
+    };
+    let mut number_len = ByteCounter(0);
+    write_number(&mut number_len)?;
+
+    // The precision is the minimum number of digits. If it's 0, the number 0
+    // is printed without any digits. Like glibc, count the bytes of the
+    // localized number.
+    let precision = precision(spec)?;
+    let min_digits = if precision < 0 { 1 } else { precision as usize };
+    let mut leading_zeros = min_digits.saturating_sub(number_len.0);
+    if spec.alt_form && spec.conversion_type == ConversionType::OctInt && leading_zeros == 0 {
+        // the alternative octal form must start with a 0
+        leading_zeros = 1;
+    }
+
+    // The 0 flag is ignored if a precision is given
+    let zero_pad = spec.zero_pad && precision < 0;
+    write_padded(out, spec, &[sign, alt_prefix], zero_pad, |w| {
+        write_repeated(w, '0', leading_zeros)?;
+        write_number(w)
+    })
+}
 
-        // Build the actual number (in reverse)
-        let mut rev_num = String::new();
-        let mut n = *self;
-        while n > 0 {
-            let digit = n % base;
-            n /= base;
-            rev_num.push(digits[digit as usize]);
+/// The size of the C integer type given by a length modifier
+fn int_bits(length_modifier: Option<LengthModifier>) -> u32 {
+    match length_modifier {
+        None => c_int::BITS,
+        Some(LengthModifier::Char) => c_schar::BITS,
+        Some(LengthModifier::Short) => c_short::BITS,
+        Some(LengthModifier::Long) => c_long::BITS,
+        Some(LengthModifier::LongLong | LengthModifier::Quad | LengthModifier::LongDouble) => {
+            c_longlong::BITS
         }
-        if rev_num.is_empty() {
-            rev_num.push('0');
+        Some(LengthModifier::IntMax) => i64::BITS,
+        Some(LengthModifier::Size | LengthModifier::SizeZ | LengthModifier::PtrDiff) => usize::BITS,
+        Some(LengthModifier::Exact(bits)) => bits.clamp(1, 128),
+        // int_fastN_t as defined by glibc
+        Some(LengthModifier::Fast(bits)) if bits <= 8 => 8,
+        Some(LengthModifier::Fast(bits)) if bits <= usize::BITS => usize::BITS,
+        Some(LengthModifier::Fast(bits)) => bits.clamp(64, 128),
+    }
+}
+
+/// Can a value of type `c_type` be passed where C expects an `int`?
+///
+/// C passes integer types narrower than `int` as `int` (the default argument
+/// promotions), so they are accepted too.
+pub(crate) fn is_promoted_int(c_type: CType) -> bool {
+    matches!(c_type, CType::Int(bits) if bits <= c_int::BITS)
+}
+
+/// Check that `arg` has the C type expected by the conversion and length
+/// modifier in `spec`, like gcc's `-Wformat` does
+///
+/// Without a length modifier, integer conversions and `%c` take an `int`,
+/// or any narrower integer type (see [is_promoted_int]).
+pub(crate) fn check_c_type(arg: &dyn Printf, spec: &ConversionSpecifier) -> Result<()> {
+    let c_type = arg.c_type().ok_or(PrintfError::WrongType(None))?;
+    let ok = match spec.conversion_type {
+        ConversionType::DecInt
+        | ConversionType::UnsignedInt
+        | ConversionType::OctInt
+        | ConversionType::HexIntLower
+        | ConversionType::HexIntUpper
+        | ConversionType::BinIntLower
+        | ConversionType::BinIntUpper => match spec.length_modifier {
+            None => is_promoted_int(c_type),
+            len => c_type == CType::Int(int_bits(len)),
+        },
+        ConversionType::SciFloatLower
+        | ConversionType::SciFloatUpper
+        | ConversionType::DecFloatLower
+        | ConversionType::DecFloatUpper
+        | ConversionType::CompactFloatLower
+        | ConversionType::CompactFloatUpper
+        | ConversionType::HexFloatLower
+        | ConversionType::HexFloatUpper => {
+            // `l` has no effect on floating point conversions
+            c_type == CType::Double
+                && matches!(spec.length_modifier, None | Some(LengthModifier::Long))
         }
+        ConversionType::Char => match spec.length_modifier {
+            // wint_t
+            _ if is_wide(spec) => matches!(c_type, CType::Char | CType::Int(32)),
+            None => c_type == CType::Char || is_promoted_int(c_type),
+            _ => false,
+        },
+        ConversionType::String if c_type == CType::Null => true,
+        ConversionType::Pointer if c_type == CType::Null => true,
+        ConversionType::String => match spec.length_modifier {
+            _ if is_wide(spec) => matches!(c_type, CType::WideString(_)),
+            None => c_type == CType::String,
+            _ => false,
+        },
+        ConversionType::Pointer => c_type == CType::Pointer && spec.length_modifier.is_none(),
+        ConversionType::Count => c_type == CType::IntPtr(int_bits(spec.length_modifier)),
+        ConversionType::PercentSign => false,
+    };
+    if ok {
+        Ok(())
+    } else {
+        Err(PrintfError::WrongType(None))
+    }
+}
 
-        // Take care of padding
-        let width: usize = match spec.width {
-            NumericParam::Literal(w) => w,
-            _ => {
-                return Err(PrintfError::Unknown); // should not happen at this point!!
-            }
+/// Is `spec` a wide character or string conversion (`%lc`, `%ls`, or the
+/// synonyms `%C`, `%S`)?
+pub(crate) fn is_wide(spec: &ConversionSpecifier) -> bool {
+    match spec.conversion_char {
+        'C' | 'S' => spec.length_modifier.is_none(),
+        _ => spec.length_modifier == Some(LengthModifier::Long),
+    }
+}
+
+/// Write `arg` converted to the C integer type given by the length modifier
+/// of `spec` (`int` if there is none), like C does. Other arguments and
+/// conversions are written as they are.
+pub(crate) fn write_c_integer(
+    arg: &dyn Printf,
+    spec: &ConversionSpecifier,
+    options: &FormatOptions,
+    out: &mut dyn fmt::Write,
+) -> Result<()> {
+    let bits = match (spec.conversion_type, arg.as_int_bits()) {
+        // `int` converted to `unsigned char`
+        (ConversionType::Char, Some(bits)) if !is_wide(spec) && spec.length_modifier.is_none() => {
+            return (bits as u8).write_with(spec, options, out);
         }
-        .try_into()
-        .unwrap_or_default();
-        let formatted = if spec.left_adj {
-            let mut num_str = prefix + &rev_num.chars().rev().collect::<String>();
-            while num_str.len() < width {
-                num_str.push(' ');
-            }
-            num_str
-        } else if spec.zero_pad {
-            while prefix.len() + rev_num.len() < width {
-                rev_num.push('0');
-            }
-            prefix + &rev_num.chars().rev().collect::<String>()
-        } else {
-            let mut num_str = prefix + &rev_num.chars().rev().collect::<String>();
-            while num_str.len() < width {
-                num_str = " ".to_owned() + &num_str;
-            }
-            num_str
-        };
+        (
+            ConversionType::DecInt
+            | ConversionType::UnsignedInt
+            | ConversionType::OctInt
+            | ConversionType::HexIntLower
+            | ConversionType::HexIntUpper
+            | ConversionType::BinIntLower
+            | ConversionType::BinIntUpper,
+            Some(bits),
+        ) => bits,
+        _ => return arg.write_with(spec, options, out),
+    };
+    let width = int_bits(spec.length_modifier);
+    // truncate, then sign-extend for signed conversions
+    let shift = u128::BITS - width;
+    if spec.conversion_type == ConversionType::DecInt {
+        (((bits << shift) as i128) >> shift).write_with(spec, options, out)
+    } else {
+        ((bits << shift) >> shift).write_with(spec, options, out)
+    }
+}
 
-        Ok(formatted)
+impl Printf for u64 {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
+        write_int(out, spec, &options.locale, *self as u128, false)
     }
     fn as_int(&self) -> Option<i32> {
         i32::try_from(*self).ok()
     }
+    fn as_int_bits(&self) -> Option<u128> {
+        Some(*self as u128)
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::Int(u64::BITS))
+    }
 }
 
 impl Printf for i64 {
-    fn format(&self, spec: &ConversionSpecifier) -> Result<String> {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
         match spec.conversion_type {
             // signed integer format
-            ConversionType::DecInt => {
-                // do I need a sign prefix?
-                let negative = *self < 0;
-                let abs_val = self.abs();
-                let sign_prefix = if negative {
-                    "-"
-                } else if spec.force_sign {
-                    "+"
-                } else if spec.space_sign {
-                    " "
-                } else {
-                    ""
-                }
-                .to_owned();
-                let mut mod_spec = *spec;
-                mod_spec.width = match spec.width {
-                    NumericParam::Literal(w) => NumericParam::Literal(w - sign_prefix.len() as i32),
-                    _ => {
-                        return Err(PrintfError::Unknown);
-                    }
-                };
-
-                let formatted = (abs_val as u64).format(&mod_spec)?;
-                // put the sign a after any leading spaces
-                let mut actual_number = &formatted[0..];
-                let mut leading_spaces = &formatted[0..0];
-                if let Some(first_non_space) = formatted.find(|c| c != ' ') {
-                    actual_number = &formatted[first_non_space..];
-                    leading_spaces = &formatted[0..first_non_space];
-                }
-                Ok(leading_spaces.to_owned() + &sign_prefix + actual_number)
-            }
+            ConversionType::DecInt => write_int(
+                out,
+                spec,
+                &options.locale,
+                self.unsigned_abs() as u128,
+                *self < 0,
+            ),
             // unsigned-only formats
-            ConversionType::HexIntLower | ConversionType::HexIntUpper | ConversionType::OctInt => {
-                (*self as u64).format(spec)
-            }
-            _ => Err(PrintfError::WrongType),
+            ConversionType::UnsignedInt
+            | ConversionType::HexIntLower
+            | ConversionType::HexIntUpper
+            | ConversionType::BinIntLower
+            | ConversionType::BinIntUpper
+            | ConversionType::OctInt
+            | ConversionType::Pointer => (*self as u64).write_with(spec, options, out),
+            _ => Err(PrintfError::WrongType(None)),
         }
     }
     fn as_int(&self) -> Option<i32> {
         i32::try_from(*self).ok()
     }
+    fn as_int_bits(&self) -> Option<u128> {
+        Some(*self as u128)
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::Int(i64::BITS))
+    }
 }
 
 impl Printf for i32 {
-    fn format(&self, spec: &ConversionSpecifier) -> Result<String> {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
         match spec.conversion_type {
             // signed integer format
-            ConversionType::DecInt => (*self as i64).format(spec),
+            ConversionType::DecInt => (*self as i64).write_with(spec, options, out),
             // unsigned-only formats
-            ConversionType::HexIntLower | ConversionType::HexIntUpper | ConversionType::OctInt => {
-                (*self as u32).format(spec)
-            }
-            _ => Err(PrintfError::WrongType),
+            ConversionType::UnsignedInt
+            | ConversionType::HexIntLower
+            | ConversionType::HexIntUpper
+            | ConversionType::BinIntLower
+            | ConversionType::BinIntUpper
+            | ConversionType::OctInt
+            | ConversionType::Pointer => (*self as u32).write_with(spec, options, out),
+            // `int` converted to `unsigned char`, like C
+            ConversionType::Char => (*self as u8).write_with(spec, options, out),
+            _ => Err(PrintfError::WrongType(None)),
         }
     }
     fn as_int(&self) -> Option<i32> {
         Some(*self)
     }
+    fn as_int_bits(&self) -> Option<u128> {
+        Some(*self as u128)
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::Int(i32::BITS))
+    }
 }
 
 impl Printf for u32 {
-    fn format(&self, spec: &ConversionSpecifier) -> Result<String> {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
         match spec.conversion_type {
             ConversionType::Char => {
                 if let Some(c) = char::from_u32(*self) {
-                    c.format(spec)
+                    c.write_with(spec, options, out)
                 } else {
-                    Err(PrintfError::WrongType)
+                    Err(PrintfError::WrongType(None))
                 }
             }
-            _ => (*self as u64).format(spec),
+            _ => (*self as u64).write_with(spec, options, out),
         }
     }
     fn as_int(&self) -> Option<i32> {
         i32::try_from(*self).ok()
     }
+    fn as_int_bits(&self) -> Option<u128> {
+        Some(*self as u128)
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::Int(u32::BITS))
+    }
 }
 
 impl Printf for i16 {
-    fn format(&self, spec: &ConversionSpecifier) -> Result<String> {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
         match spec.conversion_type {
             // signed integer format
-            ConversionType::DecInt => (*self as i64).format(spec),
+            ConversionType::DecInt => (*self as i64).write_with(spec, options, out),
             // unsigned-only formats
-            ConversionType::HexIntLower | ConversionType::HexIntUpper | ConversionType::OctInt => {
-                (*self as u16).format(spec)
-            }
-            _ => Err(PrintfError::WrongType),
+            ConversionType::UnsignedInt
+            | ConversionType::HexIntLower
+            | ConversionType::HexIntUpper
+            | ConversionType::BinIntLower
+            | ConversionType::BinIntUpper
+            | ConversionType::OctInt
+            | ConversionType::Pointer => (*self as u16).write_with(spec, options, out),
+            // `int` converted to `unsigned char`, like C
+            ConversionType::Char => (*self as u8).write_with(spec, options, out),
+            _ => Err(PrintfError::WrongType(None)),
         }
     }
     fn as_int(&self) -> Option<i32> {
         Some(*self as i32)
     }
+    fn as_int_bits(&self) -> Option<u128> {
+        Some(*self as u128)
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::Int(i16::BITS))
+    }
 }
 
 impl Printf for u16 {
-    fn format(&self, spec: &ConversionSpecifier) -> Result<String> {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
         match spec.conversion_type {
             ConversionType::Char => {
                 if let Some(Ok(c)) = char::decode_utf16([*self]).next() {
-                    c.format(spec)
+                    c.write_with(spec, options, out)
                 } else {
-                    Err(PrintfError::WrongType)
+                    Err(PrintfError::WrongType(None))
                 }
             }
-            _ => (*self as u64).format(spec),
+            _ => (*self as u64).write_with(spec, options, out),
         }
     }
     fn as_int(&self) -> Option<i32> {
         Some(*self as i32)
     }
+    fn as_int_bits(&self) -> Option<u128> {
+        Some(*self as u128)
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::Int(u16::BITS))
+    }
 }
 
 impl Printf for i8 {
-    fn format(&self, spec: &ConversionSpecifier) -> Result<String> {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
         match spec.conversion_type {
             // signed integer format
-            ConversionType::DecInt => (*self as i64).format(spec),
+            ConversionType::DecInt => (*self as i64).write_with(spec, options, out),
             // unsigned-only formats
-            ConversionType::HexIntLower | ConversionType::HexIntUpper | ConversionType::OctInt => {
-                (*self as u8).format(spec)
-            }
+            ConversionType::UnsignedInt
+            | ConversionType::HexIntLower
+            | ConversionType::HexIntUpper
+            | ConversionType::BinIntLower
+            | ConversionType::BinIntUpper
+            | ConversionType::OctInt
+            | ConversionType::Pointer => (*self as u8).write_with(spec, options, out),
             // c_char
-            ConversionType::Char => (*self as u8).format(spec),
-            _ => Err(PrintfError::WrongType),
+            ConversionType::Char => (*self as u8).write_with(spec, options, out),
+            _ => Err(PrintfError::WrongType(None)),
         }
     }
     fn as_int(&self) -> Option<i32> {
         Some(*self as i32)
     }
+    fn as_int_bits(&self) -> Option<u128> {
+        Some(*self as u128)
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::Int(i8::BITS))
+    }
 }
 
 impl Printf for u8 {
-    fn format(&self, spec: &ConversionSpecifier) -> Result<String> {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
         match spec.conversion_type {
             ConversionType::Char => {
                 if self.is_ascii() {
-                    char::from(*self).format(spec)
+                    char::from(*self).write_with(spec, options, out)
                 } else {
-                    Err(PrintfError::WrongType)
+                    Err(PrintfError::WrongType(None))
                 }
             }
-            _ => (*self as u64).format(spec),
+            _ => (*self as u64).write_with(spec, options, out),
         }
     }
     fn as_int(&self) -> Option<i32> {
         Some(*self as i32)
     }
+    fn as_int_bits(&self) -> Option<u128> {
+        Some(*self as u128)
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::Int(u8::BITS))
+    }
 }
 
 impl Printf for usize {
-    fn format(&self, spec: &ConversionSpecifier) -> Result<String> {
-        (*self as u64).format(spec)
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
+        (*self as u64).write_with(spec, options, out)
     }
     fn as_int(&self) -> Option<i32> {
         i32::try_from(*self).ok()
     }
+    fn as_int_bits(&self) -> Option<u128> {
+        Some(*self as u128)
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::Int(usize::BITS))
+    }
 }
 
 impl Printf for isize {
-    fn format(&self, spec: &ConversionSpecifier) -> Result<String> {
-        (*self as u64).format(spec)
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
+        match spec.conversion_type {
+            // signed integer format
+            ConversionType::DecInt => (*self as i64).write_with(spec, options, out),
+            // unsigned-only formats
+            _ => (*self as usize).write_with(spec, options, out),
+        }
     }
     fn as_int(&self) -> Option<i32> {
         i32::try_from(*self).ok()
     }
+    fn as_int_bits(&self) -> Option<u128> {
+        Some(*self as u128)
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::Int(isize::BITS))
+    }
 }
 
-impl Printf for f64 {
-    fn format(&self, spec: &ConversionSpecifier) -> Result<String> {
-        let mut prefix = String::new();
-        let mut number = String::new();
+impl Printf for u128 {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
+        write_int(out, spec, &options.locale, *self, false)
+    }
+    fn as_int(&self) -> Option<i32> {
+        i32::try_from(*self).ok()
+    }
+    fn as_int_bits(&self) -> Option<u128> {
+        Some(*self)
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::Int(u128::BITS))
+    }
+}
 
-        // set up the sign
-        if self.is_sign_negative() {
-            prefix.push('-');
-        } else if spec.space_sign {
-            prefix.push(' ');
-        } else if spec.force_sign {
-            prefix.push('+');
+impl Printf for i128 {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
+        match spec.conversion_type {
+            // signed integer format
+            ConversionType::DecInt => {
+                write_int(out, spec, &options.locale, self.unsigned_abs(), *self < 0)
+            }
+            // unsigned-only formats
+            ConversionType::UnsignedInt
+            | ConversionType::HexIntLower
+            | ConversionType::HexIntUpper
+            | ConversionType::BinIntLower
+            | ConversionType::BinIntUpper
+            | ConversionType::OctInt
+            | ConversionType::Pointer => (*self as u128).write_with(spec, options, out),
+            _ => Err(PrintfError::WrongType(None)),
         }
+    }
+    fn as_int(&self) -> Option<i32> {
+        i32::try_from(*self).ok()
+    }
+    fn as_int_bits(&self) -> Option<u128> {
+        Some(*self as u128)
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::Int(i128::BITS))
+    }
+}
 
-        if self.is_finite() {
-            let mut use_scientific = false;
-            let mut exp_symb = 'e';
-            let mut strip_trailing_0s = false;
-            let mut abs = self.abs();
-            let mut exponent = abs.log10().floor() as i32;
-            let mut precision = match spec.precision {
-                NumericParam::Literal(p) => p,
-                _ => {
-                    return Err(PrintfError::Unknown);
-                }
-            };
-            if precision <= 0 {
-                precision = 0;
-            }
-            match spec.conversion_type {
-                ConversionType::DecFloatLower | ConversionType::DecFloatUpper => {
-                    // default
+/// `bool` is promoted to `int`, as in C
+impl Printf for bool {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
+        (*self as c_int).write_with(spec, options, out)
+    }
+    fn as_int(&self) -> Option<i32> {
+        Some(*self as i32)
+    }
+    fn as_int_bits(&self) -> Option<u128> {
+        Some(*self as u128)
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::Int(c_int::BITS))
+    }
+}
+
+/// The non-zero integers are formatted like the plain ones
+macro_rules! impl_printf_for_nonzero {
+    ($($t:ty),*) => {
+        $(
+            impl Printf for $t {
+                fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+                    self.get().write_to(spec, out)
                 }
-                ConversionType::SciFloatLower => {
-                    use_scientific = true;
+                fn write_with(
+                    &self,
+                    spec: &ConversionSpecifier,
+                    options: &FormatOptions,
+                    out: &mut dyn fmt::Write,
+                ) -> Result<()> {
+                    self.get().write_with(spec, options, out)
                 }
-                ConversionType::SciFloatUpper => {
-                    use_scientific = true;
-                    exp_symb = 'E';
+                fn as_int(&self) -> Option<i32> {
+                    self.get().as_int()
                 }
-                ConversionType::CompactFloatLower | ConversionType::CompactFloatUpper => {
-                    if spec.conversion_type == ConversionType::CompactFloatUpper {
-                        exp_symb = 'E'
-                    }
-                    strip_trailing_0s = true;
-                    if precision == 0 {
-                        precision = 1;
-                    }
-                    // exponent signifies significant digits - we must round now
-                    // to (re)calculate the exponent
-                    let rounding_factor = 10.0_f64.powf((precision - 1 - exponent) as f64);
-                    let rounded_fixed = (abs * rounding_factor).round();
-                    abs = rounded_fixed / rounding_factor;
-                    exponent = abs.log10().floor() as i32;
-                    if exponent < -4 || exponent >= precision {
-                        use_scientific = true;
-                        precision -= 1;
-                    } else {
-                        // precision specifies the number of significant digits
-                        precision -= 1 + exponent;
-                    }
+                fn as_int_bits(&self) -> Option<u128> {
+                    self.get().as_int_bits()
                 }
-                _ => {
-                    return Err(PrintfError::WrongType);
+                fn c_type(&self) -> Option<CType> {
+                    self.get().c_type()
                 }
             }
+        )*
+    };
+}
 
-            if use_scientific {
-                let mut normal = abs / 10.0_f64.powf(exponent as f64);
-
-                if precision > 0 {
-                    let mut int_part = normal.trunc();
-                    let mut exp_factor = 10.0_f64.powf(precision as f64);
-                    let mut tail = ((normal - int_part) * exp_factor).round() as u64;
-                    while tail >= exp_factor as u64 {
-                        // Overflow, must round
-                        int_part += 1.0;
-                        tail -= exp_factor as u64;
-                        if int_part >= 10.0 {
-                            // keep same precision - which means changing exponent
-                            exponent += 1;
-                            exp_factor /= 10.0;
-                            normal /= 10.0;
-                            int_part = normal.trunc();
-                            tail = ((normal - int_part) * exp_factor).round() as u64;
-                        }
-                    }
+impl_printf_for_nonzero!(
+    NonZeroI8,
+    NonZeroU8,
+    NonZeroI16,
+    NonZeroU16,
+    NonZeroI32,
+    NonZeroU32,
+    NonZeroI64,
+    NonZeroU64,
+    NonZeroI128,
+    NonZeroU128,
+    NonZeroIsize,
+    NonZeroUsize
+);
 
-                    let mut rev_tail_str = String::new();
-                    for _ in 0..precision {
-                        rev_tail_str.push((b'0' + (tail % 10) as u8) as char);
-                        tail /= 10;
-                    }
-                    number.push_str(&format!("{}", int_part));
-                    number.push('.');
-                    number.push_str(&rev_tail_str.chars().rev().collect::<String>());
-                    if strip_trailing_0s {
-                        number = number.trim_end_matches('0').to_owned();
-                    }
-                } else {
-                    number.push_str(&format!("{}", normal.round()));
+/// The wrapping and saturating integers are formatted like the plain ones
+macro_rules! impl_printf_for_wrapper {
+    ($($t:ident),*) => {
+        $(
+            impl<T: Printf> Printf for $t<T> {
+                fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+                    self.0.write_to(spec, out)
                 }
-                number.push(exp_symb);
-                number.push_str(&format!("{:+03}", exponent));
-            } else {
-                if precision > 0 {
-                    let mut int_part = abs.trunc();
-                    let exp_factor = 10.0_f64.powf(precision as f64);
-                    let mut tail = ((abs - int_part) * exp_factor).round() as u64;
-                    let mut rev_tail_str = String::new();
-                    if tail >= exp_factor as u64 {
-                        // overflow - we must round up
-                        int_part += 1.0;
-                        tail -= exp_factor as u64;
-                        // no need to change the exponent as we don't have one
-                        // (not scientific notation)
-                    }
-                    for _ in 0..precision {
-                        rev_tail_str.push((b'0' + (tail % 10) as u8) as char);
-                        tail /= 10;
-                    }
-                    number.push_str(&format!("{}", int_part));
-                    number.push('.');
-                    number.push_str(&rev_tail_str.chars().rev().collect::<String>());
-                    if strip_trailing_0s {
-                        number = number.trim_end_matches('0').to_owned();
-                    }
-                } else {
-                    number.push_str(&format!("{}", abs.round()));
+                fn write_with(
+                    &self,
+                    spec: &ConversionSpecifier,
+                    options: &FormatOptions,
+                    out: &mut dyn fmt::Write,
+                ) -> Result<()> {
+                    self.0.write_with(spec, options, out)
                 }
-            }
-        } else {
-            // not finite
-            match spec.conversion_type {
-                ConversionType::DecFloatLower
-                | ConversionType::SciFloatLower
-                | ConversionType::CompactFloatLower => {
-                    if self.is_infinite() {
-                        number.push_str("inf")
-                    } else {
-                        number.push_str("nan")
-                    }
+                fn as_int(&self) -> Option<i32> {
+                    self.0.as_int()
                 }
-                ConversionType::DecFloatUpper
-                | ConversionType::SciFloatUpper
-                | ConversionType::CompactFloatUpper => {
-                    if self.is_infinite() {
-                        number.push_str("INF")
-                    } else {
-                        number.push_str("NAN")
-                    }
+                fn as_int_bits(&self) -> Option<u128> {
+                    self.0.as_int_bits()
                 }
-                _ => {
-                    return Err(PrintfError::WrongType);
+                fn c_type(&self) -> Option<CType> {
+                    self.0.c_type()
                 }
             }
-        }
-        // Take care of padding
-        let width: usize = match spec.width {
-            NumericParam::Literal(w) => w,
-            _ => {
-                return Err(PrintfError::Unknown); // should not happen at this point!!
-            }
-        }
-        .try_into()
-        .unwrap_or_default();
-        let formatted = if spec.left_adj {
-            let mut full_num = prefix + &number;
-            while full_num.len() < width {
-                full_num.push(' ');
+        )*
+    };
+}
+
+impl_printf_for_wrapper!(Wrapping, Saturating);
+
+impl Printf for f64 {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
+        let locale = &options.locale;
+        // set up the sign
+        let sign = if self.is_sign_negative() {
+            "-"
+        } else if spec.force_sign {
+            "+"
+        } else if spec.space_sign {
+            " "
+        } else {
+            ""
+        };
+
+        let upper = matches!(
+            spec.conversion_type,
+            ConversionType::DecFloatUpper
+                | ConversionType::SciFloatUpper
+                | ConversionType::CompactFloatUpper
+                | ConversionType::HexFloatUpper
+        );
+
+        match spec.conversion_type {
+            ConversionType::DecFloatLower
+            | ConversionType::DecFloatUpper
+            | ConversionType::SciFloatLower
+            | ConversionType::SciFloatUpper
+            | ConversionType::CompactFloatLower
+            | ConversionType::CompactFloatUpper
+                if self.is_finite() =>
+            {
+                let number = DecimalFloat::new(
+                    self.abs(),
+                    spec.conversion_type,
+                    precision(spec)?,
+                    spec.alt_form,
+                );
+                let int_digits = if spec.thousands_grouping {
+                    let mut leading = LeadingDigits {
+                        count: 0,
+                        done: false,
+                    };
+                    number.write(&mut leading)?;
+                    leading.count
+                } else {
+                    0
+                };
+                write_padded(out, spec, &[sign], spec.zero_pad, |w| {
+                    number.write(&mut Localizer {
+                        out: w,
+                        locale,
+                        digits: spec.locale_digits,
+                        int_digits,
+                    })
+                })
             }
-            full_num
-        } else if spec.zero_pad && self.is_finite() {
-            while prefix.len() + number.len() < width {
-                prefix.push('0');
+            ConversionType::HexFloatLower | ConversionType::HexFloatUpper if self.is_finite() => {
+                let number = HexFloat::new(self.abs(), precision(spec)?, spec.alt_form, upper);
+                let hex_prefix = if upper { "0X" } else { "0x" };
+                write_padded(out, spec, &[sign, hex_prefix], spec.zero_pad, |w| {
+                    number.write(&mut Localizer {
+                        out: w,
+                        locale,
+                        digits: false,
+                        int_digits: 0,
+                    })
+                })
             }
-            prefix + &number
-        } else {
-            let mut full_num = prefix + &number;
-            while full_num.len() < width {
-                full_num = " ".to_owned() + &full_num;
+            ConversionType::DecFloatLower
+            | ConversionType::DecFloatUpper
+            | ConversionType::SciFloatLower
+            | ConversionType::SciFloatUpper
+            | ConversionType::CompactFloatLower
+            | ConversionType::CompactFloatUpper
+            | ConversionType::HexFloatLower
+            | ConversionType::HexFloatUpper => {
+                // not finite
+                let number = if self.is_infinite() {
+                    locale.inf
+                } else {
+                    locale.nan
+                };
+                write_padded(out, spec, &[sign], false, |w| {
+                    if upper {
+                        number
+                            .chars()
+                            .flat_map(char::to_uppercase)
+                            .try_for_each(|c| w.write_char(c))
+                    } else {
+                        w.write_str(number)
+                    }
+                })
             }
-            full_num
-        };
-        Ok(formatted)
+            _ => Err(PrintfError::WrongType(None)),
+        }
     }
     fn as_int(&self) -> Option<i32> {
         None
     }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::Double)
+    }
 }
 
 impl Printf for f32 {
-    fn format(&self, spec: &ConversionSpecifier) -> Result<String> {
-        (*self as f64).format(spec)
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
+        (*self as f64).write_with(spec, options, out)
     }
     fn as_int(&self) -> Option<i32> {
         None
     }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::Double)
+    }
 }
 
-impl Printf for &str {
-    fn format(&self, spec: &ConversionSpecifier) -> Result<String> {
+impl Printf for str {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
         if spec.conversion_type == ConversionType::String {
-            let mut s = String::new();
-
             // Take care of precision, putting the truncated string in `content`
-            let precision: usize = match spec.precision {
-                NumericParam::Literal(p) => p,
-                _ => {
-                    return Err(PrintfError::Unknown); // should not happen at this point!!
-                }
-            }
-            .try_into()
-            .unwrap_or_default();
-            let content_len = {
-                let mut content_len = precision.min(self.len());
-                while !self.is_char_boundary(content_len) {
-                    content_len -= 1;
+            // (no precision means no limit)
+            let precision: usize = precision(spec)?.try_into().unwrap_or(usize::MAX);
+            let (content, len) = match options.text_width {
+                TextWidth::Bytes => {
+                    let mut content_len = precision.min(self.len());
+                    while !self.is_char_boundary(content_len) {
+                        content_len -= 1;
+                    }
+                    (&self[..content_len], content_len)
                 }
-                content_len
-            };
-            let content = &self[..content_len];
-
-            // Pad to width if needed, putting the padded string in `s`
-            let width: usize = match spec.width {
-                NumericParam::Literal(w) => w,
-                _ => {
-                    return Err(PrintfError::Unknown); // should not happen at this point!!
+                TextWidth::Chars => {
+                    let content_len = self
+                        .char_indices()
+                        .nth(precision)
+                        .map_or(self.len(), |(i, _)| i);
+                    let content = &self[..content_len];
+                    (content, content.chars().count())
                 }
-            }
-            .try_into()
-            .unwrap_or_default();
-            if spec.left_adj {
-                s.push_str(content);
-                while s.len() < width {
-                    s.push(' ');
+                TextWidth::Utf16 => {
+                    let mut units = 0;
+                    let content_len = self
+                        .char_indices()
+                        .find(|(_, c)| {
+                            units += c.len_utf16();
+                            units > precision
+                        })
+                        .map_or(self.len(), |(i, _)| i);
+                    let content = &self[..content_len];
+                    (content, content.encode_utf16().count())
                 }
-            } else {
-                while s.len() + content.len() < width {
-                    s.push(' ');
+                #[cfg(feature = "unicode")]
+                TextWidth::Columns => {
+                    let content_len = self
+                        .grapheme_indices(true)
+                        .nth(precision)
+                        .map_or(self.len(), |(i, _)| i);
+                    let content = &self[..content_len];
+                    (content, content.width())
                 }
-                s.push_str(content);
-            }
+            };
 
-            Ok(s)
+            // Pad to width if needed
+            write_padded_to_len(out, spec, &[], false, len, |w| w.write_str(content))
         } else {
-            Err(PrintfError::WrongType)
+            Err(PrintfError::WrongType(None))
         }
     }
     fn as_int(&self) -> Option<i32> {
         None
     }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::String)
+    }
 }
 
 impl Printf for char {
-    fn format(&self, spec: &ConversionSpecifier) -> Result<String> {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
         if spec.conversion_type == ConversionType::Char {
-            let mut s = String::new();
-
-            let width: usize = match spec.width {
-                NumericParam::Literal(w) => w,
-                _ => {
-                    return Err(PrintfError::Unknown); // should not happen at this point!!
-                }
-            }
-            .try_into()
-            .unwrap_or_default();
-
-            if spec.left_adj {
-                s.push(*self);
-                while s.len() < width {
-                    s.push(' ');
-                }
-            } else {
-                while s.len() + self.len_utf8() < width {
-                    s.push(' ');
-                }
-                s.push(*self);
-            }
-            Ok(s)
+            let len = match options.text_width {
+                TextWidth::Bytes => self.len_utf8(),
+                TextWidth::Chars => 1,
+                TextWidth::Utf16 => self.len_utf16(),
+                #[cfg(feature = "unicode")]
+                TextWidth::Columns => self.width().unwrap_or(0),
+            };
+            write_padded_to_len(out, spec, &[], false, len, |w| w.write_char(*self))
         } else {
-            Err(PrintfError::WrongType)
+            Err(PrintfError::WrongType(None))
         }
     }
     fn as_int(&self) -> Option<i32> {
         None
     }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::Char)
+    }
 }
 
+#[cfg(feature = "alloc")]
 impl Printf for String {
-    fn format(&self, spec: &ConversionSpecifier) -> Result<String> {
-        (self as &str).format(spec)
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.as_str().write_to(spec, out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
+        self.as_str().write_with(spec, options, out)
     }
     fn as_int(&self) -> Option<i32> {
         None
     }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::String)
+    }
 }
 
-impl Printf for &CStr {
-    fn format(&self, spec: &ConversionSpecifier) -> Result<String> {
+impl Printf for CStr {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
         if let Ok(s) = self.to_str() {
-            s.format(spec)
+            s.write_with(spec, options, out)
         } else {
-            Err(PrintfError::WrongType)
+            Err(PrintfError::WrongType(None))
         }
     }
     fn as_int(&self) -> Option<i32> {
         None
     }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::String)
+    }
+    fn as_byte_str(&self) -> Option<&[u8]> {
+        Some(self.to_bytes())
+    }
 }
 
+#[cfg(feature = "alloc")]
 impl Printf for CString {
-    fn format(&self, spec: &ConversionSpecifier) -> Result<String> {
-        self.as_c_str().format(spec)
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.as_c_str().write_to(spec, out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
+        self.as_c_str().write_with(spec, options, out)
     }
     fn as_int(&self) -> Option<i32> {
         None
     }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::String)
+    }
+    fn as_byte_str(&self) -> Option<&[u8]> {
+        Some(self.as_bytes())
+    }
+}
+
+/// Wide strings (`wchar_t *`) in UTF-16, for `%ls`; they must be valid
+/// UTF-16
+#[cfg(feature = "alloc")]
+impl Printf for [u16] {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
+        let s = char::decode_utf16(self.iter().copied())
+            .collect::<core::result::Result<String, _>>()
+            .map_err(|_| PrintfError::WrongType(None))?;
+        s.as_str().write_with(spec, options, out)
+    }
+    fn as_int(&self) -> Option<i32> {
+        None
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::WideString(16))
+    }
+}
+
+/// Wide strings (`wchar_t *`) in UTF-32, for `%ls`; they must be valid
+/// UTF-32
+#[cfg(feature = "alloc")]
+impl Printf for [u32] {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
+        let s = self
+            .iter()
+            .map(|&c| char::from_u32(c))
+            .collect::<Option<String>>()
+            .ok_or(PrintfError::WrongType(None))?;
+        s.as_str().write_with(spec, options, out)
+    }
+    fn as_int(&self) -> Option<i32> {
+        None
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::WideString(32))
+    }
+}
+
+/// Byte strings can only be written as text if they are valid UTF-8, except
+/// by the byte-oriented functions
+impl Printf for [u8] {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
+        if let Ok(s) = str::from_utf8(self) {
+            s.write_with(spec, options, out)
+        } else {
+            Err(PrintfError::WrongType(None))
+        }
+    }
+    fn as_int(&self) -> Option<i32> {
+        None
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::String)
+    }
+    fn as_byte_str(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Printf for OsStr {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.as_encoded_bytes().write_to(spec, out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
+        self.as_encoded_bytes().write_with(spec, options, out)
+    }
+    fn as_int(&self) -> Option<i32> {
+        None
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::String)
+    }
+    fn as_byte_str(&self) -> Option<&[u8]> {
+        Some(self.as_encoded_bytes())
+    }
+}
+
+#[cfg(feature = "std")]
+impl Printf for OsString {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.as_os_str().write_to(spec, out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
+        self.as_os_str().write_with(spec, options, out)
+    }
+    fn as_int(&self) -> Option<i32> {
+        None
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::String)
+    }
+    fn as_byte_str(&self) -> Option<&[u8]> {
+        Some(self.as_encoded_bytes())
+    }
+}
+
+#[cfg(feature = "std")]
+impl Printf for Path {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.as_os_str().write_to(spec, out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
+        self.as_os_str().write_with(spec, options, out)
+    }
+    fn as_int(&self) -> Option<i32> {
+        None
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::String)
+    }
+    fn as_byte_str(&self) -> Option<&[u8]> {
+        Some(self.as_os_str().as_encoded_bytes())
+    }
+}
+
+#[cfg(feature = "std")]
+impl Printf for PathBuf {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.as_path().write_to(spec, out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
+        self.as_path().write_with(spec, options, out)
+    }
+    fn as_int(&self) -> Option<i32> {
+        None
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::String)
+    }
+    fn as_byte_str(&self) -> Option<&[u8]> {
+        Some(self.as_os_str().as_encoded_bytes())
+    }
 }
 
 impl<T> Printf for *const T {
-    fn format(&self, spec: &ConversionSpecifier) -> Result<String> {
-        (*self as usize).format(spec)
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        (*self as usize).write_to(spec, out)
     }
     fn as_int(&self) -> Option<i32> {
         None
     }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::Pointer)
+    }
 }
 
 impl<T> Printf for *mut T {
-    fn format(&self, spec: &ConversionSpecifier) -> Result<String> {
-        (*self as usize).format(spec)
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        (*self as usize).write_to(spec, out)
     }
     fn as_int(&self) -> Option<i32> {
         None
     }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::Pointer)
+    }
+}
+
+/// `%n` stores the count in a `Cell`, truncated to the integer type like C
+macro_rules! impl_printf_for_count_cell {
+    ($($t:ty),*) => {
+        $(
+            impl Printf for Cell<$t> {
+                fn write_to(&self, _spec: &ConversionSpecifier, _out: &mut dyn fmt::Write) -> Result<()> {
+                    Err(PrintfError::WrongType(None))
+                }
+                fn as_int(&self) -> Option<i32> {
+                    None
+                }
+                fn c_type(&self) -> Option<CType> {
+                    Some(CType::IntPtr(<$t>::BITS))
+                }
+                fn store_count(&self, count: usize) -> Result<()> {
+                    self.set(count as $t);
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+impl_printf_for_count_cell!(i8, u8, i16, u16, i32, u32, i64, u64, isize, usize);
+
+/// `Some` is formatted like the value it holds, and `None` like a null
+/// pointer in glibc: `(nil)` for `%p`, and [FormatOptions::null_text] for
+/// `%s`. Any other conversion of `None` is [PrintfError::WrongType].
+impl<T: Printf> Printf for Option<T> {
+    fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+        self.write_with(spec, &FormatOptions::default(), out)
+    }
+    fn write_with(
+        &self,
+        spec: &ConversionSpecifier,
+        options: &FormatOptions,
+        out: &mut dyn fmt::Write,
+    ) -> Result<()> {
+        match (self, spec.conversion_type) {
+            (Some(value), _) => value.write_with(spec, options, out),
+            (None, ConversionType::Pointer) => write_int(out, spec, &options.locale, 0, false),
+            (None, ConversionType::String) => {
+                // like glibc, write all of the text or nothing
+                let text = match precision(spec)? {
+                    p if p >= 0 && (p as usize) < options.null_text.len() => "",
+                    _ => options.null_text,
+                };
+                let spec = ConversionSpecifier {
+                    precision: NumericParam::Literal(-1),
+                    ..*spec
+                };
+                text.write_with(&spec, options, out)
+            }
+            (None, _) => Err(PrintfError::WrongType(None)),
+        }
+    }
+    fn as_int(&self) -> Option<i32> {
+        self.as_ref()?.as_int()
+    }
+    fn as_int_bits(&self) -> Option<u128> {
+        self.as_ref()?.as_int_bits()
+    }
+    fn c_type(&self) -> Option<CType> {
+        match self {
+            Some(value) => value.c_type(),
+            None => Some(CType::Null),
+        }
+    }
+    fn store_count(&self, count: usize) -> Result<()> {
+        match self {
+            Some(value) => value.store_count(count),
+            None => Err(PrintfError::WrongType(None)),
+        }
+    }
+    fn as_byte_str(&self) -> Option<&[u8]> {
+        self.as_ref()?.as_byte_str()
+    }
+}
+
+/// References and smart pointers are formatted like the value they point to
+macro_rules! impl_printf_for_pointer {
+    ($($t:ty $(where T: $bound:path)?;)*) => {
+        $(
+            impl<'a, T: Printf + ?Sized> Printf for $t $(where T: $bound)? {
+                fn write_to(&self, spec: &ConversionSpecifier, out: &mut dyn fmt::Write) -> Result<()> {
+                    (**self).write_to(spec, out)
+                }
+                fn write_with(
+                    &self,
+                    spec: &ConversionSpecifier,
+                    options: &FormatOptions,
+                    out: &mut dyn fmt::Write,
+                ) -> Result<()> {
+                    (**self).write_with(spec, options, out)
+                }
+                fn as_int(&self) -> Option<i32> {
+                    (**self).as_int()
+                }
+                fn as_int_bits(&self) -> Option<u128> {
+                    (**self).as_int_bits()
+                }
+                fn c_type(&self) -> Option<CType> {
+                    (**self).c_type()
+                }
+                fn store_count(&self, count: usize) -> Result<()> {
+                    (**self).store_count(count)
+                }
+                fn as_byte_str(&self) -> Option<&[u8]> {
+                    (**self).as_byte_str()
+                }
+            }
+        )*
+    };
+}
+
+impl_printf_for_pointer!(&'a T; &'a mut T;);
+#[cfg(feature = "alloc")]
+impl_printf_for_pointer!(Box<T>; Rc<T>; Cow<'a, T> where T: ToOwned;);
+#[cfg(all(feature = "alloc", target_has_atomic = "ptr"))]
+impl_printf_for_pointer!(Arc<T>;);
+
+#[cfg(target_has_atomic = "ptr")]
+impl Printf for AtomicUsize {
+    fn write_to(&self, _spec: &ConversionSpecifier, _out: &mut dyn fmt::Write) -> Result<()> {
+        Err(PrintfError::WrongType(None))
+    }
+    fn as_int(&self) -> Option<i32> {
+        None
+    }
+    fn c_type(&self) -> Option<CType> {
+        Some(CType::IntPtr(usize::BITS))
+    }
+    fn store_count(&self, count: usize) -> Result<()> {
+        self.store(count, Ordering::Relaxed);
+        Ok(())
+    }
 }
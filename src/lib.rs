@@ -6,10 +6,20 @@
 //!
 //! It follows the standard C semantics, except:
 //!
-//!  * Locale-aware UNIX extensions (`'` and GNU’s `I`) are not supported.
-//!  * `%a`/`%A` (hexadecimal floating point) are currently not implemented.
-//!  * Length modifiers (`h`, `l`, etc.) are checked, but ignored. The passed
-//!    type is used instead.
+//!  * The locale-aware flags (`'` and GNU’s `I`) use the C locale unless a
+//!    [Locale] is given in the [FormatOptions].
+//!  * Length modifiers (`h`, `l`, etc.) are checked, but ignored by default.
+//!    The passed type is used instead. Set
+//!    [FormatOptions::apply_length_modifiers] to convert integers like C.
+//!
+//! Numbered arguments (`%2$s`, `%*3$d`) are supported following the POSIX
+//! rules: numbered and unnumbered conversions cannot be mixed, and all
+//! arguments must be used, but an argument may be used more than once.
+//!
+//! `%n` stores the number of bytes written so far in its argument, which
+//! must be a [Cell](core::cell::Cell) of an integer type or an
+//! [AtomicUsize](core::sync::atomic::AtomicUsize). It can be disabled with
+//! [FormatOptions::forbid_count].
 //!
 //! Usage example:
 //!
@@ -18,39 +28,405 @@
 //!     assert_eq!(s, "3 + 9 = 12\n");
 //!
 //! The types of the arguments are checked at runtime.
+//! To parse a format string once and keep it, e.g. in a cache, create a
+//! [CompiledFormat]. To also check it against the argument types, create a
+//! [Format].
+//!
+//! To write the output to an existing buffer or stream instead of a new
+//! `String`, use [vwritef] or [vwritef_io].
+//!
+//! To format byte strings that may not be valid UTF-8, like file names, as
+//! C would, use [vsprintf_bytes].
+//! For wide strings (`wchar_t`), use [vswprintf_utf16] or [vswprintf_utf32];
+//! wide string arguments for `%ls` are given as `&[u16]` or `&[u32]`.
 //!
+//! ## Features
+//!
+//!  * `std` (default): [vwritef_io], and [PrintfError] implements
+//!    `std::error::Error`. Implies `alloc`.
+//!  * `alloc`: everything that returns a `String` or `Vec`, including
+//!    [vsprintf] and [sprintf!], and `Printf` for `String` and `CString`.
+//!  * `unicode`: `TextWidth::Columns`, to pad strings to a width in display
+//!    columns.
+//!  * `cache`: keep the parsed format strings used by [vsprintf] and
+//!    [vwritef] in a per-thread cache (see the `cache` module). Implies
+//!    `std`.
+//!
+//! Without either, the crate is `no_std` and never allocates: format into a
+//! [core::fmt::Write] with [vwritef] or into a byte buffer with [vsnprintf].
+//!
+
+#![no_std]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+#[cfg(feature = "alloc")]
+use alloc::{string::String, vec::Vec};
+use core::fmt::{self, Write as _};
+#[cfg(feature = "std")]
+use std::io;
 
 use thiserror::Error;
 
+#[cfg(feature = "cache")]
+pub mod cache;
+pub mod checked;
+#[cfg(feature = "alloc")]
+mod compiled;
+mod float;
 mod format;
+mod locale;
 pub mod parser;
+#[cfg(feature = "alloc")]
+mod typed;
 
-pub use format::Printf;
-use parser::{parse_format_string, FormatElement};
+#[cfg(feature = "alloc")]
+pub use compiled::{ArgRole, ArgUse, CompiledFormat};
+pub use format::{CType, Printf};
+pub use locale::Locale;
+#[cfg(feature = "alloc")]
+use parser::ByteFormatElements;
+use parser::{count_args, FormatElement, FormatElements, Located, Position};
 #[doc(hidden)]
-pub use parser::{ConversionSpecifier, ConversionType, NumericParam};
+pub use parser::{ConversionSpecifier, ConversionType, LengthModifier, NumericParam};
+pub use parser::{ParseError, ParseErrorReason};
+#[cfg(feature = "alloc")]
+pub use typed::{ArgList, Format, FormatArg};
 
 /// Error type
 #[derive(Debug, Clone, Copy, Error, PartialEq, Eq)]
 pub enum PrintfError {
-    /// Error parsing the format string
-    #[error("Error parsing the format string")]
-    ParseError,
-    /// Incorrect type passed as an argument
-    #[error("Incorrect type passed as an argument")]
-    WrongType,
+    /// Error parsing the format string, with where and why
+    #[error("Error parsing the format string: {0}")]
+    ParseError(ParseError),
+    /// Incorrect type passed as an argument, with the argument and its
+    /// conversion (`None` if the error comes from a [Printf] method called
+    /// directly)
+    #[error("Incorrect type passed as an argument{}", OptionalArgContext(.0))]
+    WrongType(Option<ArgContext>),
     /// Too many arguments passed
     #[error("Too many arguments passed")]
     TooManyArgs,
-    /// Too few arguments passed
-    #[error("Too few arguments passed")]
-    NotEnoughArgs,
+    /// Too few arguments passed, with the first missing argument and the
+    /// conversion that needs it
+    #[error("Too few arguments passed: no {0}")]
+    NotEnoughArgs(ArgContext),
+    /// Error writing the output
+    #[error("Error writing the output")]
+    WriteError,
     /// Other error (should never happen)
     #[error("Other error (should never happen)")]
     Unknown,
 }
 
-pub type Result<T> = std::result::Result<T, PrintfError>;
+impl From<fmt::Error> for PrintfError {
+    fn from(_: fmt::Error) -> Self {
+        PrintfError::WriteError
+    }
+}
+
+impl PrintfError {
+    /// Add the argument an error from a [Printf] method occurred for
+    fn for_arg(self, index: usize, spec: &ConversionSpecifier) -> Self {
+        match self {
+            PrintfError::WrongType(None) => {
+                PrintfError::WrongType(Some(ArgContext { index, spec: *spec }))
+            }
+            e => e,
+        }
+    }
+}
+
+/// The argument an error occurred for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArgContext {
+    /// The (zero-based) index of the argument
+    pub index: usize,
+    /// The conversion specifier using the argument
+    pub spec: ConversionSpecifier,
+}
+
+impl fmt::Display for ArgContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "argument {} for `{}`", self.index + 1, self.spec)
+    }
+}
+
+/// Displays the context of [PrintfError::WrongType], if any
+struct OptionalArgContext<'a>(&'a Option<ArgContext>);
+
+impl fmt::Display for OptionalArgContext<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(context) => write!(f, ": {}", context),
+            None => Ok(()),
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, PrintfError>;
+
+/// Options for formatting
+///
+/// The functions [vsprintf], [vwritef], etc. use the default options; the
+/// methods of the same names use the given ones.
+///
+///     use sprintf::{FormatOptions, Printf};
+///     let options = FormatOptions {
+///         apply_length_modifiers: true,
+///         ..Default::default()
+///     };
+///     let s = options.vsprintf("%hhx", &[&300 as &dyn Printf]).unwrap();
+///     assert_eq!(s, "2c");
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatOptions<'a> {
+    /// Convert integer arguments to the type given by the length modifier
+    /// (or `int` if there is none) before formatting them, as C does: `%hd`
+    /// of 70000 is `4464`, and `%c` of 321 is `A`. If not set, the type of
+    /// the argument is used and the length modifier is ignored.
+    pub apply_length_modifiers: bool,
+    /// Check the type of every argument against its conversion and length
+    /// modifier before writing anything, like gcc's `-Wformat`, and fail
+    /// with [PrintfError::WrongType] if it doesn't match: `%lld` requires a
+    /// 64-bit integer, and `%hd` a 16-bit one. As in C, integers narrower
+    /// than `int` are promoted, so `%d`, `%c` and `*` also take them.
+    /// Arguments whose [Printf::c_type] is `None` are rejected.
+    pub strict: bool,
+    /// Reject format strings containing `%n` with [PrintfError::ParseError]
+    /// before writing anything. Use this for format strings from untrusted
+    /// sources.
+    pub forbid_count: bool,
+    /// The locale to use for numbers (see [Locale])
+    pub locale: Locale<'a>,
+    /// How to count the width and precision of strings and characters
+    pub text_width: TextWidth,
+    /// The text written for `%s` of a `None` argument, `(null)` by default like
+    /// glibc. As in glibc, it is padded to the width like a string, but
+    /// never truncated: if the precision is smaller than its length, nothing
+    /// is written. `%p` of `None` is `(nil)`.
+    pub null_text: &'a str,
+}
+
+impl Default for FormatOptions<'_> {
+    fn default() -> Self {
+        FormatOptions {
+            apply_length_modifiers: false,
+            strict: false,
+            forbid_count: false,
+            locale: Locale::C,
+            text_width: TextWidth::Bytes,
+            null_text: "(null)",
+        }
+    }
+}
+
+impl FormatOptions<'_> {
+    /// Like [vsprintf], with these options
+    #[cfg(feature = "alloc")]
+    pub fn vsprintf(&self, format: &str, args: &[&dyn Printf]) -> Result<String> {
+        #[cfg(feature = "cache")]
+        return cache::get(format)?.vsprintf_with(self, args);
+        #[cfg(not(feature = "cache"))]
+        {
+            let mut res = String::new();
+            self.vwritef(&mut res, format, args)?;
+            Ok(res)
+        }
+    }
+
+    /// Like [vsprintfp], with these options
+    #[cfg(feature = "alloc")]
+    pub fn vsprintfp(&self, format: &[FormatElement], args: &[&dyn Printf]) -> Result<String> {
+        let mut res = String::new();
+        self.vwritefp(&mut res, format, args)?;
+        Ok(res)
+    }
+
+    /// Like [vwritef], with these options
+    pub fn vwritef<W: fmt::Write>(
+        &self,
+        out: &mut W,
+        format: &str,
+        args: &[&dyn Printf],
+    ) -> Result<()> {
+        #[cfg(feature = "cache")]
+        return cache::get(format)?.vwritef_with(self, out, args);
+        #[cfg(not(feature = "cache"))]
+        write_formatted(
+            Sink::Text(out),
+            FormatElements::new(format).located(),
+            args,
+            self,
+        )
+    }
+
+    /// Like [vwritefp], with these options
+    pub fn vwritefp<W: fmt::Write>(
+        &self,
+        out: &mut W,
+        format: &[FormatElement],
+        args: &[&dyn Printf],
+    ) -> Result<()> {
+        write_formatted(Sink::Text(out), locate(format), args, self)
+    }
+
+    /// Like [vsprintf_bytes], with these options
+    #[cfg(feature = "alloc")]
+    pub fn vsprintf_bytes(&self, format: &[u8], args: &[&dyn Printf]) -> Result<Vec<u8>> {
+        let mut res = Vec::new();
+        write_formatted(
+            Sink::Bytes(&mut res),
+            ByteFormatElements::new(format).located(),
+            args,
+            self,
+        )?;
+        Ok(res)
+    }
+
+    /// Like [vsprintfp_bytes], with these options
+    #[cfg(feature = "alloc")]
+    pub fn vsprintfp_bytes(
+        &self,
+        format: &[FormatElement<[u8]>],
+        args: &[&dyn Printf],
+    ) -> Result<Vec<u8>> {
+        let mut res = Vec::new();
+        write_formatted(Sink::Bytes(&mut res), locate(format), args, self)?;
+        Ok(res)
+    }
+
+    /// Like [vswprintf_utf16], with these options
+    #[cfg(feature = "alloc")]
+    pub fn vswprintf_utf16(&self, format: &[u16], args: &[&dyn Printf]) -> Result<Vec<u16>> {
+        let mut text = String::new();
+        for c in char::decode_utf16(format.iter().copied()) {
+            let c = c.map_err(|_| invalid_encoding(text.encode_utf16().count()))?;
+            text.push(c);
+        }
+        let mut res = Vec::new();
+        write_wide(
+            Sink::Utf16(&mut res),
+            &text,
+            |s| s.encode_utf16().count(),
+            args,
+            &self.wide(TextWidth::Utf16),
+        )?;
+        Ok(res)
+    }
+
+    /// Like [vswprintf_utf32], with these options
+    #[cfg(feature = "alloc")]
+    pub fn vswprintf_utf32(&self, format: &[u32], args: &[&dyn Printf]) -> Result<Vec<u32>> {
+        let text = format
+            .iter()
+            .enumerate()
+            .map(|(i, &c)| char::from_u32(c).ok_or_else(|| invalid_encoding(i)))
+            .collect::<Result<String>>()?;
+        let mut res = Vec::new();
+        write_wide(
+            Sink::Utf32(&mut res),
+            &text,
+            |s| s.chars().count(),
+            args,
+            &self.wide(TextWidth::Chars),
+        )?;
+        Ok(res)
+    }
+
+    /// The options for wide output, where the width and precision of text
+    /// count code units of the output (like C's `wchar_t`) instead of bytes
+    #[cfg(feature = "alloc")]
+    fn wide(&self, code_units: TextWidth) -> FormatOptions<'_> {
+        let mut options = self.clone();
+        if options.text_width == TextWidth::Bytes {
+            options.text_width = code_units;
+        }
+        options
+    }
+
+    /// Like [vwritef_io], with these options
+    #[cfg(feature = "std")]
+    pub fn vwritef_io<W: io::Write>(
+        &self,
+        out: &mut W,
+        format: &str,
+        args: &[&dyn Printf],
+    ) -> io::Result<()> {
+        let mut adapter = IoAdapter::new(out);
+        let res = self.vwritef(&mut adapter, format, args);
+        // write what was formatted before an error, as without the buffer
+        let flushed = match adapter.error {
+            Some(_) => Ok(()),
+            None => adapter.flush_buf(),
+        };
+        match (res, adapter.error) {
+            (Ok(()), _) => flushed,
+            (Err(_), Some(e)) => Err(e),
+            (Err(e), None) => flushed.and(Err(io::Error::new(io::ErrorKind::InvalidInput, e))),
+        }
+    }
+
+    /// Like [vsnprintf], with these options
+    pub fn vsnprintf(
+        &self,
+        buf: &mut [u8],
+        truncation: Truncation,
+        format: &str,
+        args: &[&dyn Printf],
+    ) -> Result<usize> {
+        write_truncated(
+            buf,
+            truncation,
+            FormatElements::new(format).located(),
+            args,
+            self,
+        )
+    }
+
+    /// Like [vsnprintfp], with these options
+    pub fn vsnprintfp(
+        &self,
+        buf: &mut [u8],
+        truncation: Truncation,
+        format: &[FormatElement],
+        args: &[&dyn Printf],
+    ) -> Result<usize> {
+        write_truncated(buf, truncation, locate(format), args, self)
+    }
+}
+
+/// How the width and precision of strings and characters (`%s` and `%c`)
+/// are counted
+///
+///     use sprintf::{FormatOptions, Printf, TextWidth};
+///     let options = FormatOptions {
+///         text_width: TextWidth::Chars,
+///         ..Default::default()
+///     };
+///     let text: &dyn Printf = &"Grüße";
+///     let s = options.vsprintf("[%-6s|%.2s]", &[text, text]).unwrap();
+///     assert_eq!(s, "[Grüße |Gr]");
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TextWidth {
+    /// In bytes, like C
+    #[default]
+    Bytes,
+    /// In characters (Unicode scalar values)
+    Chars,
+    /// In UTF-16 code units, like `swprintf` with a 16-bit `wchar_t`
+    /// (Windows)
+    Utf16,
+    /// The width in display columns (so East Asian wide characters count
+    /// twice), and the precision in extended grapheme clusters, which are
+    /// never split
+    ///
+    /// Requires the `unicode` feature.
+    #[cfg(feature = "unicode")]
+    Columns,
+}
 
 /// Format a string. (Roughly equivalent to `vsnprintf` or `vasprintf` in C)
 ///
@@ -64,57 +440,723 @@ pub type Result<T> = std::result::Result<T, PrintfError>;
 ///     assert_eq!(s, "0x0010");
 ///
 /// See also: [sprintf]
+#[cfg(feature = "alloc")]
 pub fn vsprintf(format: &str, args: &[&dyn Printf]) -> Result<String> {
-    vsprintfp(&parse_format_string(format)?, args)
+    FormatOptions::default().vsprintf(format, args)
 }
 
 /// Format a string using [`parser::FormatElement`]s
 ///
 /// Like [vsprintf], except that it doesn't parse the format string.
+#[cfg(feature = "alloc")]
 pub fn vsprintfp(format: &[FormatElement], args: &[&dyn Printf]) -> Result<String> {
-    let mut res = String::new();
+    FormatOptions::default().vsprintfp(format, args)
+}
+
+/// Format a byte string, which doesn't need to be valid UTF-8
+///
+/// Like [vsprintf], but everything is copied to the output byte for byte,
+/// as C does: the format string, and byte string arguments for `%s` (like
+/// `&[u8]`, `CStr`, `OsStr` and `Path`), whose precision and width are
+/// counted in bytes. `%c` of an integer converts it to `unsigned char` and
+/// writes that byte, so `%c` of `200` is the byte `0xc8`.
+///
+///     use sprintf::{vsprintf_bytes, Printf};
+///     let name: &[u8] = b"caf\xe9";
+///     let s = vsprintf_bytes(b"[%-6s] \xff", &[&name as &dyn Printf]).unwrap();
+///     assert_eq!(s, b"[caf\xe9  ] \xff");
+#[cfg(feature = "alloc")]
+pub fn vsprintf_bytes(format: &[u8], args: &[&dyn Printf]) -> Result<Vec<u8>> {
+    FormatOptions::default().vsprintf_bytes(format, args)
+}
+
+/// Format a byte string using [`parser::FormatElement`]s
+///
+/// Like [vsprintf_bytes], except that it doesn't parse the format string.
+#[cfg(feature = "alloc")]
+pub fn vsprintfp_bytes(format: &[FormatElement<[u8]>], args: &[&dyn Printf]) -> Result<Vec<u8>> {
+    FormatOptions::default().vsprintfp_bytes(format, args)
+}
 
-    let mut args = args;
-    let mut pop_arg = || {
-        if args.is_empty() {
-            Err(PrintfError::NotEnoughArgs)
-        } else {
-            let a = args[0];
-            args = &args[1..];
-            Ok(a)
+/// Format a UTF-16 string. (Like `vswprintf` in C with a 16-bit `wchar_t`)
+///
+/// Like [vsprintf], but the format string and the output are UTF-16, and
+/// the width and precision of text count UTF-16 code units (unless
+/// [FormatOptions::text_width] says otherwise). Wide strings for `%ls` can be
+/// given as `&[u16]` (UTF-16) or `&[u32]` (UTF-32), and narrow strings for
+/// `%s` are converted.
+///
+///     use sprintf::{vswprintf_utf16, Printf};
+///     let format: Vec<u16> = "%ls=%5s".encode_utf16().collect();
+///     let name: Vec<u16> = "größe".encode_utf16().collect();
+///     let s = vswprintf_utf16(&format, &[&&name[..] as &dyn Printf, &"XL"]).unwrap();
+///     assert_eq!(String::from_utf16(&s).unwrap(), "größe=   XL");
+#[cfg(feature = "alloc")]
+pub fn vswprintf_utf16(format: &[u16], args: &[&dyn Printf]) -> Result<Vec<u16>> {
+    FormatOptions::default().vswprintf_utf16(format, args)
+}
+
+/// Format a UTF-32 string. (Like `vswprintf` in C with a 32-bit `wchar_t`)
+///
+/// Like [vswprintf_utf16], but the format string and the output are UTF-32,
+/// and the width and precision of text count characters.
+#[cfg(feature = "alloc")]
+pub fn vswprintf_utf32(format: &[u32], args: &[&dyn Printf]) -> Result<Vec<u32>> {
+    FormatOptions::default().vswprintf_utf32(format, args)
+}
+
+/// Format a string, writing the result to a [fmt::Write]
+///
+/// Like [vsprintf], except that the output is written to `out` instead of
+/// being returned as a new `String`.
+///
+///     use sprintf::{vwritef, Printf};
+///     let mut s = String::from("n = ");
+///     vwritef(&mut s, "%d", &[&42 as &dyn Printf]).unwrap();
+///     assert_eq!(s, "n = 42");
+///
+/// If an error occurs, the output may have been partially written.
+/// This does not allocate, except with the `cache` feature, where a format
+/// string that isn't in the cache is parsed and added to it, and with the
+/// `alloc` feature to check a format string with more than 4096 numbered
+/// arguments.
+pub fn vwritef<W: fmt::Write>(out: &mut W, format: &str, args: &[&dyn Printf]) -> Result<()> {
+    FormatOptions::default().vwritef(out, format, args)
+}
+
+/// Format a string using [`parser::FormatElement`]s, writing the result to a
+/// [fmt::Write]
+///
+/// Like [vwritef], except that it doesn't parse the format string.
+pub fn vwritefp<W: fmt::Write>(
+    out: &mut W,
+    format: &[FormatElement],
+    args: &[&dyn Printf],
+) -> Result<()> {
+    FormatOptions::default().vwritefp(out, format, args)
+}
+
+/// Format a string, writing the result to an [io::Write]
+///
+/// Like [vwritef], except for the type of `out`. Errors raised by `out` are
+/// passed on as they are; formatting errors are returned as an
+/// [io::Error] of kind [InvalidInput](io::ErrorKind::InvalidInput)
+/// wrapping a [PrintfError].
+///
+///     use sprintf::{vwritef_io, Printf};
+///     let mut buf = Vec::new();
+///     vwritef_io(&mut buf, "%s!\n", &[&"hello" as &dyn Printf]).unwrap();
+///     assert_eq!(buf, b"hello!\n");
+///
+/// The output is collected and written in pieces of up to 512 bytes, so
+/// `out` doesn't need to be wrapped in a [BufWriter](io::BufWriter). If an
+/// error occurs, the output may have been partially written.
+#[cfg(feature = "std")]
+pub fn vwritef_io<W: io::Write>(out: &mut W, format: &str, args: &[&dyn Printf]) -> io::Result<()> {
+    FormatOptions::default().vwritef_io(out, format, args)
+}
+
+/// Lets an [io::Write] be used as a [fmt::Write], keeping the error
+///
+/// The output is collected in a buffer and written in large pieces, so that
+/// formatting to an unbuffered writer (like a `File`) doesn't take a system
+/// call for every fragment. [IoAdapter::flush_buf] must be called at the
+/// end.
+#[cfg(feature = "std")]
+struct IoAdapter<'a, W: io::Write> {
+    inner: &'a mut W,
+    buf: [u8; 512],
+    len: usize,
+    error: Option<io::Error>,
+}
+
+#[cfg(feature = "std")]
+impl<'a, W: io::Write> IoAdapter<'a, W> {
+    fn new(inner: &'a mut W) -> Self {
+        IoAdapter {
+            inner,
+            buf: [0; 512],
+            len: 0,
+            error: None,
         }
+    }
+
+    /// Write the buffered output to the writer
+    fn flush_buf(&mut self) -> io::Result<()> {
+        let len = core::mem::take(&mut self.len);
+        self.inner.write_all(&self.buf[..len])
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: io::Write> fmt::Write for IoAdapter<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut write = || {
+            if self.len + s.len() > self.buf.len() {
+                self.flush_buf()?;
+            }
+            if s.len() > self.buf.len() {
+                self.inner.write_all(s.as_bytes())
+            } else {
+                self.buf[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+                self.len += s.len();
+                Ok(())
+            }
+        };
+        write().map_err(|e| {
+            self.error = Some(e);
+            fmt::Error
+        })
+    }
+}
+
+/// How [vsnprintf] truncates output that doesn't fit into the buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Truncation {
+    /// Cut the output after exactly as many bytes as fit, like C does. This
+    /// may split a multi-byte UTF-8 character.
+    Bytes,
+    /// Cut the output at the last character boundary that fits, so the
+    /// buffer always contains valid UTF-8.
+    CharBoundary,
+}
+
+/// Format a string into a byte buffer. (Equivalent to `vsnprintf` in C)
+///
+/// At most `buf.len() - 1` bytes of output are written to `buf`, followed by
+/// a NUL byte. Returns the length the output would have had if `buf` had
+/// been large enough (not counting the NUL byte), so the output was
+/// truncated if the return value is `>= buf.len()`. An empty `buf` can be
+/// used to only compute the length.
+///
+///     use sprintf::{vsnprintf, Printf, Truncation};
+///     let mut buf = [0xff_u8; 6];
+///     let len = vsnprintf(&mut buf, Truncation::Bytes, "%d apples", &[&12 as &dyn Printf]);
+///     assert_eq!(len, Ok(9));
+///     assert_eq!(&buf, b"12 ap\0");
+///
+/// If an error occurs, the buffer contains the output up to that point
+/// (NUL-terminated). This does not allocate, except with the `alloc`
+/// feature to check a format string with more than 4096 numbered arguments.
+pub fn vsnprintf(
+    buf: &mut [u8],
+    truncation: Truncation,
+    format: &str,
+    args: &[&dyn Printf],
+) -> Result<usize> {
+    FormatOptions::default().vsnprintf(buf, truncation, format, args)
+}
+
+/// Format a string into a byte buffer using [`parser::FormatElement`]s
+///
+/// Like [vsnprintf], except that it doesn't parse the format string.
+pub fn vsnprintfp(
+    buf: &mut [u8],
+    truncation: Truncation,
+    format: &[FormatElement],
+    args: &[&dyn Printf],
+) -> Result<usize> {
+    FormatOptions::default().vsnprintfp(buf, truncation, format, args)
+}
+
+fn write_truncated<'a>(
+    buf: &mut [u8],
+    truncation: Truncation,
+    format: impl Iterator<Item = Result<Located<'a>>> + Clone,
+    args: &[&dyn Printf],
+    options: &FormatOptions,
+) -> Result<usize> {
+    let mut writer = TruncatingWriter {
+        buf,
+        pos: 0,
+        total: 0,
+        truncation,
+        full: false,
     };
+    let res = write_formatted(Sink::Text(&mut writer), format, args, options);
+    if let Some(end) = writer.buf.get_mut(writer.pos) {
+        *end = 0;
+    }
+    res.map(|_| writer.total)
+}
+
+/// A [fmt::Write] that fills a byte buffer, leaving room for a NUL byte,
+/// and counts the bytes that didn't fit
+struct TruncatingWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+    total: usize,
+    truncation: Truncation,
+    full: bool,
+}
+
+impl fmt::Write for TruncatingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.total += s.len();
+        if self.full {
+            return Ok(());
+        }
+        let available = self.buf.len().saturating_sub(self.pos + 1);
+        let mut n = s.len();
+        if n > available {
+            n = available;
+            if self.truncation == Truncation::CharBoundary {
+                while !s.is_char_boundary(n) {
+                    n -= 1;
+                }
+            }
+            self.full = true;
+        }
+        self.buf[self.pos..self.pos + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.pos += n;
+        Ok(())
+    }
+}
+
+fn write_formatted<'a, T: FormatText + ?Sized + 'a>(
+    out: Sink,
+    format: impl Iterator<Item = Result<Located<'a, T>>> + Clone,
+    args: &[&dyn Printf],
+    options: &FormatOptions,
+) -> Result<()> {
+    // these options promise to check before writing anything
+    if options.strict {
+        validate(format.clone(), args, options)?;
+    } else if options.forbid_count {
+        check_count_forbidden(format.clone())?;
+    }
+    let mut used = Args::new(args);
+    match write_checked(out, format.clone(), &mut used, options) {
+        Ok(()) if used.all_used() => Ok(()),
+        // walk the format string again to find the error, or to check the
+        // numbered arguments that couldn't be checked while writing
+        res => {
+            validate(format, args, options)?;
+            res
+        }
+    }
+}
 
+/// Check the format string and the number of arguments, failing with the
+/// first error
+fn validate<'a, T: FormatText + ?Sized + 'a>(
+    format: impl Iterator<Item = Result<Located<'a, T>>> + Clone,
+    args: &[&dyn Printf],
+    options: &FormatOptions,
+) -> Result<()> {
+    let n_args = count_args(format.clone())?;
+    check_arg_count(format.clone(), args, n_args)?;
+    if options.forbid_count {
+        check_count_forbidden(format)?;
+    }
+    Ok(())
+}
+
+/// Check that there are `n_args` arguments, as counted by [count_args]
+fn check_arg_count<'a, T: ?Sized + 'a>(
+    format: impl Iterator<Item = Result<Located<'a, T>>>,
+    args: &[&dyn Printf],
+    n_args: usize,
+) -> Result<()> {
+    if args.len() < n_args {
+        // find the first conversion whose argument is missing
+        check_args(format, args, false)?;
+        Err(PrintfError::Unknown)
+    } else if args.len() > n_args {
+        Err(PrintfError::TooManyArgs)
+    } else {
+        Ok(())
+    }
+}
+
+/// Fail at the first `%n`, for [FormatOptions::forbid_count]
+fn check_count_forbidden<'a, T: FormatText + ?Sized + 'a>(
+    format: impl Iterator<Item = Result<Located<'a, T>>>,
+) -> Result<()> {
     for elem in format {
-        match elem {
+        let elem = elem?;
+        if let FormatElement::Format(spec) = elem.elem {
+            if spec.conversion_type == ConversionType::Count {
+                return Err(elem.error(ParseErrorReason::CountForbidden));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Format a format string, taking the arguments from `args`
+///
+/// Fails on the first error found while writing, but whether the numbering
+/// of the arguments is valid and all of them are used is left to
+/// [Args::all_used] or to [validate].
+fn write_checked<'a, T: FormatText + ?Sized + 'a>(
+    out: Sink,
+    format: impl Iterator<Item = Result<Located<'a, T>>> + Clone,
+    args: &mut Args,
+    options: &FormatOptions,
+) -> Result<()> {
+    if options.strict {
+        check_args(format.clone(), args.all, true)?;
+    }
+
+    let mut out = CountingWriter {
+        inner: out,
+        count: 0,
+    };
+    for elem in format {
+        match elem?.elem {
             FormatElement::Verbatim(s) => {
-                res.push_str(s);
+                s.write_verbatim(&mut out)?;
             }
             FormatElement::Format(spec) => {
                 if spec.conversion_type == ConversionType::PercentSign {
-                    res.push('%');
+                    out.write_char('%')?;
                 } else {
-                    let mut completed_spec = *spec;
-                    if spec.width == NumericParam::FromArgument {
-                        completed_spec.width = NumericParam::Literal(
-                            pop_arg()?.as_int().ok_or(PrintfError::WrongType)?,
-                        )
+                    let mut completed_spec = spec;
+                    if let Some(arg_number) = numeric_param_arg(spec.width) {
+                        let (index, arg) = args.get(arg_number, &spec)?;
+                        let width = arg
+                            .as_int()
+                            .ok_or(PrintfError::WrongType(None).for_arg(index, &spec))?;
+                        // a negative width is taken as the `-` flag
+                        if width < 0 {
+                            completed_spec.left_adj = true;
+                        }
+                        // `i32::MIN` has no absolute value: make it as wide as possible
+                        completed_spec.width =
+                            NumericParam::Literal(width.checked_abs().unwrap_or(i32::MAX));
                     }
-                    if spec.precision == NumericParam::FromArgument {
+                    if let Some(arg_number) = numeric_param_arg(spec.precision) {
+                        let (index, arg) = args.get(arg_number, &spec)?;
+                        // a negative precision is taken as if it were omitted
                         completed_spec.precision = NumericParam::Literal(
-                            pop_arg()?.as_int().ok_or(PrintfError::WrongType)?,
+                            arg.as_int()
+                                .ok_or(PrintfError::WrongType(None).for_arg(index, &spec))?
+                                .max(-1),
                         )
                     }
-                    res.push_str(&pop_arg()?.format(&completed_spec)?);
+                    let (index, arg) = args.get(spec.arg_number, &spec)?;
+                    // an `int` is converted to `unsigned char` and written as it
+                    // is, even if it isn't valid UTF-8
+                    let c_char = match spec.conversion_type {
+                        ConversionType::Char
+                            if out.takes_bytes()
+                                && !format::is_wide(&spec)
+                                && matches!(arg.c_type(), Some(t) if format::is_promoted_int(t)) =>
+                        {
+                            arg.as_int_bits().map(|bits| [bits as u8])
+                        }
+                        _ => None,
+                    };
+                    let byte_str = match spec.conversion_type {
+                        ConversionType::String if out.takes_bytes() => arg.as_byte_str(),
+                        _ => c_char.as_ref().map(|c| &c[..]),
+                    };
+                    if spec.conversion_type == ConversionType::Count {
+                        arg.store_count(out.count)
+                    } else if let Some(bytes) = byte_str {
+                        format::write_byte_str(&completed_spec, bytes, |b| out.write_bytes(b))
+                    } else if options.apply_length_modifiers {
+                        format::write_c_integer(arg, &completed_spec, options, &mut out)
+                    } else {
+                        arg.write_with(&completed_spec, options, &mut out)
+                    }
+                    .map_err(|e| e.for_arg(index, &spec))?;
                 }
             }
         }
     }
 
-    if args.is_empty() {
-        Ok(res)
-    } else {
-        Err(PrintfError::TooManyArgs)
+    Ok(())
+}
+
+/// Check that there is an argument for every conversion, and if `strict`,
+/// that its type matches the conversion
+fn check_args<'a, T: ?Sized + 'a>(
+    format: impl Iterator<Item = Result<Located<'a, T>>>,
+    args: &[&dyn Printf],
+    strict: bool,
+) -> Result<()> {
+    let mut args = Args::new(args);
+    for elem in format {
+        if let FormatElement::Format(spec) = elem?.elem {
+            if spec.conversion_type == ConversionType::PercentSign {
+                continue;
+            }
+            for param in [spec.width, spec.precision] {
+                if let Some(arg_number) = numeric_param_arg(param) {
+                    let (index, arg) = args.get(arg_number, &spec)?;
+                    if strict && !matches!(arg.c_type(), Some(t) if format::is_promoted_int(t)) {
+                        return Err(PrintfError::WrongType(None).for_arg(index, &spec));
+                    }
+                }
+            }
+            let (index, arg) = args.get(spec.arg_number, &spec)?;
+            if strict {
+                format::check_c_type(arg, &spec).map_err(|e| e.for_arg(index, &spec))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Format a wide format string, converted to `format`, with the positions
+/// of parse errors counted by `code_units` in the original
+#[cfg(feature = "alloc")]
+fn write_wide(
+    out: Sink,
+    format: &str,
+    code_units: impl Fn(&str) -> usize,
+    args: &[&dyn Printf],
+    options: &FormatOptions,
+) -> Result<()> {
+    write_formatted(out, FormatElements::new(format).located(), args, options).map_err(
+        |e| match e {
+            PrintfError::ParseError(e) => {
+                let offset = code_units(&format[..e.offset]);
+                PrintfError::ParseError(ParseError {
+                    offset,
+                    len: code_units(&format[..e.offset + e.len]) - offset,
+                    ..e
+                })
+            }
+            e => e,
+        },
+    )
+}
+
+/// The error for an invalid code unit in a wide format string
+#[cfg(feature = "alloc")]
+fn invalid_encoding(offset: usize) -> PrintfError {
+    PrintfError::ParseError(ParseError {
+        offset,
+        len: 1,
+        reason: ParseErrorReason::InvalidEncoding,
+    })
+}
+
+/// Parsed elements, located in the string they are displayed as if there
+/// is an error
+fn locate<'a, T: ?Sized>(
+    format: &'a [FormatElement<'a, T>],
+) -> impl Iterator<Item = Result<Located<'a, T>>> + Clone {
+    format.iter().enumerate().map(move |(index, elem)| {
+        Ok(Located {
+            elem: *elem,
+            position: Position::Parsed {
+                elements: format,
+                index,
+            },
+        })
+    })
+}
+
+/// The length of an element displayed as in the format string
+fn display_len<T: FormatText + ?Sized>(elem: &FormatElement<'_, T>) -> usize {
+    match elem {
+        FormatElement::Verbatim(s) => s.text_len(),
+        FormatElement::Format(spec) => {
+            let mut counter = LenCounter(0);
+            // writing to a LenCounter can't fail
+            let _ = write!(counter, "{}", spec);
+            counter.0
+        }
+    }
+}
+
+/// A [fmt::Write] that only counts the bytes written
+struct LenCounter(usize);
+
+impl fmt::Write for LenCounter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+/// Where [write_formatted] writes the output
+enum Sink<'a> {
+    /// Text, for the functions producing a `String`
+    Text(&'a mut dyn fmt::Write),
+    /// Bytes that may not be valid UTF-8, for the byte-oriented functions
+    #[cfg(feature = "alloc")]
+    Bytes(&'a mut Vec<u8>),
+    /// UTF-16, for [vswprintf_utf16]
+    #[cfg(feature = "alloc")]
+    Utf16(&'a mut Vec<u16>),
+    /// UTF-32, for [vswprintf_utf32]
+    #[cfg(feature = "alloc")]
+    Utf32(&'a mut Vec<u32>),
+}
+
+/// A [fmt::Write] that counts the code units (bytes, unless the output is
+/// wide) written through it, for `%n`
+struct CountingWriter<'a> {
+    inner: Sink<'a>,
+    count: usize,
+}
+
+impl CountingWriter<'_> {
+    /// Can bytes that aren't valid UTF-8 be written?
+    fn takes_bytes(&self) -> bool {
+        match self.inner {
+            #[cfg(feature = "alloc")]
+            Sink::Bytes(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Write bytes, failing with [PrintfError::WrongType] if they aren't
+    /// valid UTF-8 and only text can be written
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        match &mut self.inner {
+            #[cfg(feature = "alloc")]
+            Sink::Bytes(out) => {
+                out.extend_from_slice(bytes);
+                self.count += bytes.len();
+                Ok(())
+            }
+            _ => {
+                let s = core::str::from_utf8(bytes).map_err(|_| PrintfError::WrongType(None))?;
+                Ok(self.write_str(s)?)
+            }
+        }
+    }
+}
+
+impl fmt::Write for CountingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.count += match &mut self.inner {
+            Sink::Text(out) => {
+                out.write_str(s)?;
+                s.len()
+            }
+            #[cfg(feature = "alloc")]
+            Sink::Bytes(out) => {
+                out.extend_from_slice(s.as_bytes());
+                s.len()
+            }
+            #[cfg(feature = "alloc")]
+            Sink::Utf16(out) => {
+                let start = out.len();
+                out.extend(s.encode_utf16());
+                out.len() - start
+            }
+            #[cfg(feature = "alloc")]
+            Sink::Utf32(out) => {
+                out.extend(s.chars().map(u32::from));
+                s.chars().count()
+            }
+        };
+        Ok(())
+    }
+}
+
+/// The type of the verbatim parts of a format string
+trait FormatText {
+    fn write_verbatim(&self, out: &mut CountingWriter) -> Result<()>;
+    fn text_len(&self) -> usize;
+}
+
+impl FormatText for str {
+    fn write_verbatim(&self, out: &mut CountingWriter) -> Result<()> {
+        Ok(out.write_str(self)?)
+    }
+    fn text_len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl FormatText for [u8] {
+    fn write_verbatim(&self, out: &mut CountingWriter) -> Result<()> {
+        out.write_bytes(self)
+    }
+    fn text_len(&self) -> usize {
+        self.len()
+    }
+}
+
+/// The arguments, taken in order or by number
+struct Args<'a, 'b> {
+    all: &'b [&'a dyn Printf],
+    next: &'b [&'a dyn Printf],
+    /// Whether the arguments taken so far were numbered, if all were
+    numbered: Option<bool>,
+    mixed: bool,
+    max_arg: usize,
+    /// Bit `n - 1` for each argument number `n` taken, up to 128
+    referenced: u128,
+}
+
+impl<'a, 'b> Args<'a, 'b> {
+    fn new(args: &'b [&'a dyn Printf]) -> Self {
+        Args {
+            all: args,
+            next: args,
+            numbered: None,
+            mixed: false,
+            max_arg: 0,
+            referenced: 0,
+        }
+    }
+
+    /// Were all the arguments taken, without mixing numbered and unnumbered
+    /// ones?
+    ///
+    /// This is `false` if it can't be known: with more than 128 numbered
+    /// arguments.
+    fn all_used(&self) -> bool {
+        match self.numbered {
+            _ if self.mixed => false,
+            Some(true) => {
+                self.max_arg == self.all.len()
+                    && self.max_arg <= 128
+                    && self.referenced == u128::MAX >> (128 - self.max_arg)
+            }
+            _ => self.next.is_empty(),
+        }
+    }
+
+    /// Get the argument with the given number, or the next one, with its
+    /// index, for the conversion `spec`
+    fn get(
+        &mut self,
+        arg_number: Option<usize>,
+        spec: &ConversionSpecifier,
+    ) -> Result<(usize, &'a dyn Printf)> {
+        if *self.numbered.get_or_insert(arg_number.is_some()) != arg_number.is_some() {
+            self.mixed = true;
+        }
+        if let Some(n) = arg_number {
+            self.max_arg = self.max_arg.max(n);
+            if n <= 128 {
+                self.referenced |= 1 << (n - 1);
+            }
+        }
+        let index = match arg_number {
+            Some(n) => n - 1,
+            None => {
+                let index = self.all.len() - self.next.len();
+                self.next = self.next.get(1..).unwrap_or_default();
+                index
+            }
+        };
+        match self.all.get(index) {
+            Some(&arg) => Ok((index, arg)),
+            None => Err(PrintfError::NotEnoughArgs(ArgContext {
+                index,
+                spec: *spec,
+            })),
+        }
+    }
+}
+
+/// If `param` is taken from an argument, get the argument number (if any)
+fn numeric_param_arg(param: NumericParam) -> Option<Option<usize>> {
+    match param {
+        NumericParam::Literal(_) => None,
+        NumericParam::FromArgument => Some(None),
+        NumericParam::FromNumberedArgument(n) => Some(Some(n)),
     }
 }
 
@@ -128,6 +1170,7 @@ pub fn vsprintfp(format: &[FormatElement], args: &[&dyn Printf]) -> Result<Strin
 ///     assert_eq!(s, "forty-two =   42");
 ///
 /// Wrapper around [vsprintf].
+#[cfg(feature = "alloc")]
 #[macro_export]
 macro_rules! sprintf {
     ($fmt:expr, $($arg:expr),*) => {
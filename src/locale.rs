@@ -0,0 +1,127 @@
+//! Locale-dependent parts of number formatting
+
+use core::fmt;
+
+/// Description of a locale, as far as printf is concerned
+///
+/// The decimal point and the spelling of infinity and NaN are always used;
+/// digit grouping only with the `'` flag, and the digits only with the
+/// (GNU) `I` flag.
+///
+///     use sprintf::{FormatOptions, Locale, Printf};
+///     let de_de = Locale {
+///         decimal_point: ",",
+///         thousands_sep: ".",
+///         grouping: &[3],
+///         ..Locale::C
+///     };
+///     let options = FormatOptions {
+///         locale: de_de,
+///         ..Default::default()
+///     };
+///     let s = options.vsprintf("%'.2f", &[&1234567.891 as &dyn Printf]).unwrap();
+///     assert_eq!(s, "1.234.567,89");
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Locale<'a> {
+    /// The decimal point
+    pub decimal_point: &'a str,
+    /// The separator between groups of digits (for the `'` flag)
+    pub thousands_sep: &'a str,
+    /// The sizes of the groups of digits, starting at the decimal point. The
+    /// last size is repeated, so most locales use `[3]`, and Indian locales
+    /// `[3, 2]`. Empty means no grouping.
+    pub grouping: &'a [u8],
+    /// The digits 0 to 9 (for the `I` flag)
+    pub digits: [&'a str; 10],
+    /// How to write infinity (in lower case)
+    pub inf: &'a str,
+    /// How to write NaN (in lower case)
+    pub nan: &'a str,
+}
+
+impl Locale<'static> {
+    /// The C (or POSIX) locale, which is used by default
+    pub const C: Locale<'static> = Locale {
+        decimal_point: ".",
+        thousands_sep: "",
+        grouping: &[],
+        digits: ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"],
+        inf: "inf",
+        nan: "nan",
+    };
+}
+
+impl Default for Locale<'_> {
+    fn default() -> Self {
+        Locale::C
+    }
+}
+
+impl Locale<'_> {
+    /// Is there a group separator after the digit that has `n` more integer
+    /// digits after it?
+    fn is_group_boundary(&self, n: usize) -> bool {
+        let mut pos = 0;
+        let mut sizes = self.grouping.iter();
+        let mut size = 0;
+        while pos < n {
+            size = sizes.next().map_or(size, |s| *s as usize);
+            if size == 0 {
+                return false;
+            }
+            pos += size;
+        }
+        n > 0 && pos == n
+    }
+}
+
+/// A [fmt::Write] that localizes the number written to it
+///
+/// The decimal point is replaced; if `digits` is set, the digits are
+/// replaced, and separators are inserted between the first `int_digits`
+/// characters written according to the grouping.
+pub(crate) struct Localizer<'a, 'l> {
+    pub out: &'a mut dyn fmt::Write,
+    pub locale: &'a Locale<'l>,
+    pub digits: bool,
+    pub int_digits: usize,
+}
+
+impl fmt::Write for Localizer<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c.to_digit(10) {
+                Some(d) if self.digits => self.out.write_str(self.locale.digits[d as usize])?,
+                _ if c == '.' => self.out.write_str(self.locale.decimal_point)?,
+                _ => self.out.write_char(c)?,
+            }
+            if self.int_digits > 0 {
+                self.int_digits -= 1;
+                if self.locale.is_group_boundary(self.int_digits) {
+                    self.out.write_str(self.locale.thousands_sep)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A [fmt::Write] that counts the digits at the start of what's written to
+/// it, i.e., the integer part of a number
+pub(crate) struct LeadingDigits {
+    pub count: usize,
+    pub done: bool,
+}
+
+impl fmt::Write for LeadingDigits {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.done || !c.is_ascii_digit() {
+                self.done = true;
+                break;
+            }
+            self.count += 1;
+        }
+        Ok(())
+    }
+}
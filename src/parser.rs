@@ -1,18 +1,36 @@
 //! Parse printf format strings
 
-use crate::{PrintfError, Result};
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use core::{fmt, iter, ops::Range, str};
+
+use thiserror::Error;
+
+use crate::{display_len, FormatText, PrintfError, Result};
 
 /// A part of a format string: either a string of characters to be included
 /// verbatim, or a format specifier that should be replaced based on an argument
 /// to the [vsprintf](crate::vsprintf) call.
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum FormatElement<'a> {
+///
+/// The verbatim parts are `str`, or `[u8]` for byte format strings (see
+/// [parse_format_bytes]).
+#[derive(Debug, PartialEq, Eq)]
+pub enum FormatElement<'a, T: ?Sized = str> {
     /// Some characters that are copied to the output as-is
-    Verbatim(&'a str),
+    Verbatim(&'a T),
     /// A format specifier
     Format(ConversionSpecifier),
 }
 
+impl<T: ?Sized> Clone for FormatElement<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: ?Sized> Copy for FormatElement<'_, T> {}
+
 /// Parsed printf conversion specifier
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ConversionSpecifier {
@@ -26,12 +44,74 @@ pub struct ConversionSpecifier {
     pub space_sign: bool,
     /// flag `+`: Always show sign? (for signed numbers)
     pub force_sign: bool,
+    /// flag `'`: group the digits of the integer part (according to the
+    /// locale)
+    pub thousands_grouping: bool,
+    /// flag `I` (GNU): use the digits of the locale
+    pub locale_digits: bool,
     /// field width
     pub width: NumericParam,
-    /// floating point field precision
+    /// precision (negative if not given, meaning the default for the
+    /// conversion)
     pub precision: NumericParam,
+    /// length modifier (`hh`, `l`, ...), if any
+    pub length_modifier: Option<LengthModifier>,
     /// data type
     pub conversion_type: ConversionType,
+    /// the conversion character exactly as written in the format string
+    pub conversion_char: char,
+    /// argument number (`n$`), counting from 1 as in the format string
+    pub arg_number: Option<usize>,
+}
+
+/// Writes the specifier back out as it appeared in the format string (in
+/// canonical form: flags in a fixed order, no redundant width or precision)
+///
+///     use sprintf::parser::FormatElements;
+///     let fmt = "%2$-#08.*3$llx%%";
+///     let out: String = FormatElements::new(fmt).map(|e| e.unwrap().to_string()).collect();
+///     assert_eq!(out, fmt);
+impl fmt::Display for ConversionSpecifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("%")?;
+        if let Some(n) = self.arg_number {
+            write!(f, "{}$", n)?;
+        }
+        for (set, flag) in [
+            (self.left_adj, "-"),
+            (self.alt_form, "#"),
+            (self.zero_pad, "0"),
+            (self.space_sign, " "),
+            (self.force_sign, "+"),
+            (self.thousands_grouping, "'"),
+            (self.locale_digits, "I"),
+        ] {
+            if set {
+                f.write_str(flag)?;
+            }
+        }
+        match self.width {
+            NumericParam::Literal(0) => {}
+            w => w.fmt(f)?,
+        }
+        match self.precision {
+            NumericParam::Literal(p) if p < 0 => {}
+            p => write!(f, ".{}", p)?,
+        }
+        if let Some(len) = self.length_modifier {
+            len.fmt(f)?;
+        }
+        write!(f, "{}", self.conversion_char)
+    }
+}
+
+impl fmt::Display for FormatElement<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatElement::Verbatim(s) => f.write_str(s),
+            FormatElement::Format(spec) => spec.fmt(f),
+        }
+    }
 }
 
 /// Width / precision parameter
@@ -41,21 +121,108 @@ pub enum NumericParam {
     Literal(i32),
     /// Get the width from the previous argument
     ///
+    /// As in C, a negative width taken from an argument means left-adjust
+    /// (the `-` flag), and a negative precision means the precision is
+    /// omitted. The argument can be any integer that fits in an `i32`; a
+    /// width of `i32::MIN` is taken as `-i32::MAX`.
+    ///
     /// This should never be passed to [Printf::format()][crate::Printf::format()].
     FromArgument,
+    /// Get the width from the argument with the given number (`*m$`),
+    /// counting from 1
+    ///
+    /// This should never be passed to [Printf::format()][crate::Printf::format()].
+    FromNumberedArgument(usize),
+}
+
+impl fmt::Display for NumericParam {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NumericParam::Literal(n) => write!(f, "{}", n),
+            NumericParam::FromArgument => f.write_str("*"),
+            NumericParam::FromNumberedArgument(n) => write!(f, "*{}$", n),
+        }
+    }
+}
+
+/// Length modifier
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LengthModifier {
+    /// `hh`
+    Char,
+    /// `h`
+    Short,
+    /// `l`
+    Long,
+    /// `ll`
+    LongLong,
+    /// `q`
+    Quad,
+    /// `L`
+    LongDouble,
+    /// `j`
+    IntMax,
+    /// `z`
+    Size,
+    /// `Z`
+    SizeZ,
+    /// `t`
+    PtrDiff,
+    /// `wN` (C23): exactly N bits, like `intN_t`
+    Exact(u32),
+    /// `wfN` (C23): the fastest type with at least N bits, like
+    /// `int_fastN_t`
+    Fast(u32),
+}
+
+/// The length modifiers that are written as fixed strings
+const LENGTH_MODIFIERS: [(&str, LengthModifier); 10] = [
+    ("hh", LengthModifier::Char),
+    ("h", LengthModifier::Short),
+    ("ll", LengthModifier::LongLong),
+    ("l", LengthModifier::Long),
+    ("q", LengthModifier::Quad),
+    ("L", LengthModifier::LongDouble),
+    ("j", LengthModifier::IntMax),
+    ("z", LengthModifier::Size),
+    ("Z", LengthModifier::SizeZ),
+    ("t", LengthModifier::PtrDiff),
+];
+
+/// Writes the length modifier as it appears in a format string
+impl fmt::Display for LengthModifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LengthModifier::Exact(n) => write!(f, "w{}", n),
+            LengthModifier::Fast(n) => write!(f, "wf{}", n),
+            _ => {
+                let (s, _) = LENGTH_MODIFIERS
+                    .iter()
+                    .find(|(_, len)| len == self)
+                    .ok_or(fmt::Error)?;
+                f.write_str(s)
+            }
+        }
+    }
 }
 
 /// Printf data type
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ConversionType {
-    /// `d`, `i`, or `u`
+    /// `d` or `i`
     DecInt,
+    /// `u`
+    UnsignedInt,
     /// `o`
     OctInt,
-    /// `x` or `p`
+    /// `x`
     HexIntLower,
     /// `X`
     HexIntUpper,
+    /// `b` (C23)
+    BinIntLower,
+    /// `B` (C23)
+    BinIntUpper,
     /// `e`
     SciFloatLower,
     /// `E`
@@ -68,14 +235,163 @@ pub enum ConversionType {
     CompactFloatLower,
     /// `G`
     CompactFloatUpper,
+    /// `a`
+    HexFloatLower,
+    /// `A`
+    HexFloatUpper,
     /// `c`
     Char,
     /// `s`
     String,
+    /// `p`
+    Pointer,
+    /// `n`: store the number of bytes written so far in the argument
+    Count,
     /// `%`
     PercentSign,
 }
 
+/// An error in a format string
+///
+/// The offset and length are in bytes, or in code units for wide format
+/// strings. For format strings given as [FormatElement]s, they are
+/// positions in the string the elements are displayed as.
+///
+///     use sprintf::{vsprintf, ParseErrorReason, PrintfError};
+///     match vsprintf("%d%% done, %y left", &[&50, &3]) {
+///         Err(PrintfError::ParseError(e)) => {
+///             assert_eq!(e.span(), 11..13);
+///             assert_eq!(e.reason, ParseErrorReason::UnknownConversion);
+///         }
+///         res => panic!("{:?}", res),
+///     }
+#[derive(Debug, Clone, Copy, Error, PartialEq, Eq)]
+#[error("{reason} at offset {offset}")]
+pub struct ParseError {
+    /// The offset of the invalid conversion specifier in the format string
+    pub offset: usize,
+    /// The length of the invalid conversion specifier, up to where the
+    /// error was found
+    pub len: usize,
+    /// What is wrong with it
+    pub reason: ParseErrorReason,
+}
+
+impl ParseError {
+    /// The range of the invalid conversion specifier in the format string
+    pub fn span(&self) -> Range<usize> {
+        self.offset..self.offset + self.len
+    }
+}
+
+/// Why a format string is invalid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorReason {
+    /// An unknown conversion character (`%y`)
+    UnknownConversion,
+    /// The format string ends in a conversion specifier (`%` or `%-5`)
+    DanglingPercent,
+    /// More than one length modifier (`%lhd`)
+    DuplicateLengthModifier,
+    /// A `wN` or `wfN` length modifier with an unsupported `N` (`%w7d`)
+    InvalidLengthModifier,
+    /// An argument number of 0 (`%0$d`)
+    BadArgNumber,
+    /// Numbered and unnumbered conversions are mixed (`%1$d %d`)
+    MixedArgNumbers,
+    /// The argument with this number is never used, but a higher numbered
+    /// one is (`%2$d` without `%1$`)
+    UnusedArgNumber(usize),
+    /// A `%n` conversion, with
+    /// [FormatOptions::forbid_count](crate::FormatOptions::forbid_count)
+    CountForbidden,
+    /// A wide format string that isn't valid UTF-16 or UTF-32
+    InvalidEncoding,
+}
+
+impl fmt::Display for ParseErrorReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorReason::UnknownConversion => f.write_str("unknown conversion"),
+            ParseErrorReason::DanglingPercent => f.write_str("incomplete conversion specifier"),
+            ParseErrorReason::DuplicateLengthModifier => {
+                f.write_str("more than one length modifier")
+            }
+            ParseErrorReason::InvalidLengthModifier => f.write_str("invalid length modifier"),
+            ParseErrorReason::BadArgNumber => f.write_str("invalid argument number"),
+            ParseErrorReason::MixedArgNumbers => {
+                f.write_str("numbered and unnumbered arguments mixed")
+            }
+            ParseErrorReason::UnusedArgNumber(n) => write!(f, "argument {} is never used", n),
+            ParseErrorReason::CountForbidden => f.write_str("`%n` not allowed"),
+            ParseErrorReason::InvalidEncoding => f.write_str("invalid wide character"),
+        }
+    }
+}
+
+impl From<ParseError> for PrintfError {
+    fn from(e: ParseError) -> Self {
+        PrintfError::ParseError(e)
+    }
+}
+
+/// A [FormatElement] and its position in the format string, for errors
+pub(crate) struct Located<'a, T: ?Sized = str> {
+    pub elem: FormatElement<'a, T>,
+    pub position: Position<'a, T>,
+}
+
+/// Where a [Located] element is in the format string
+pub(crate) enum Position<'a, T: ?Sized> {
+    /// The offset and length, found while parsing
+    Known { offset: usize, len: usize },
+    /// Element `index` of already parsed `elements`, located in the string
+    /// they are displayed as only when there is an error
+    Parsed {
+        elements: &'a [FormatElement<'a, T>],
+        index: usize,
+    },
+}
+
+impl<T: ?Sized> Clone for Located<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: ?Sized> Copy for Located<'_, T> {}
+
+impl<T: ?Sized> Clone for Position<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: ?Sized> Copy for Position<'_, T> {}
+
+impl<T: FormatText + ?Sized> Located<'_, T> {
+    /// The error for this element
+    pub fn error(&self, reason: ParseErrorReason) -> PrintfError {
+        let (offset, len) = match self.position {
+            Position::Known { offset, len } => (offset, len),
+            Position::Parsed { elements, index } => (
+                elements[..index].iter().map(display_len).sum(),
+                display_len(&elements[index]),
+            ),
+        };
+        PrintfError::ParseError(ParseError {
+            offset,
+            len,
+            reason,
+        })
+    }
+}
+
+/// The result of parsing part of a conversion specifier: the value and the
+/// rest of the string, or the error and the rest of the string after the
+/// invalid part
+type SpecResult<'a, T> = core::result::Result<(T, &'a str), (ParseErrorReason, &'a str)>;
+
 /// Parses a string to a vector of [FormatElement]
 ///
 /// Takes a printf-style format string `fmt`
@@ -94,49 +410,362 @@ pub enum ConversionType {
 ///             left_adj: false,
 ///             space_sign: false,
 ///             force_sign: false,
+///             thousands_grouping: false,
+///             locale_digits: false,
 ///             width: NumericParam::Literal(6),
-///             precision: NumericParam::Literal(6),
+///             precision: NumericParam::Literal(-1),
+///             length_modifier: None,
 ///             conversion_type: ConversionType::HexIntLower,
+///             conversion_char: 'x',
+///             arg_number: None,
 ///         })
 ///     );
 ///
+#[cfg(feature = "alloc")]
 pub fn parse_format_string(fmt: &str) -> Result<Vec<FormatElement<'_>>> {
-    // find the first %
-    let mut res = Vec::new();
+    count_args(FormatElements::new(fmt).located())?;
+    FormatElements::new(fmt).collect()
+}
+
+/// Iterator over the [FormatElement]s of a format string, parsing lazily
+///
+/// This does the same as [parse_format_string] without allocating, but
+/// doesn't check the use of numbered arguments across the whole string.
+///
+///     use sprintf::parser::{FormatElement, FormatElements};
+///     let mut elements = FormatElements::new("%d%%");
+///     assert!(matches!(elements.next(), Some(Ok(FormatElement::Format(_)))));
+///     assert!(matches!(elements.next(), Some(Ok(FormatElement::Format(_)))));
+///     assert!(elements.next().is_none());
+#[derive(Debug, Clone)]
+pub struct FormatElements<'a> {
+    rem: &'a str,
+    offset: usize,
+}
+
+impl<'a> FormatElements<'a> {
+    /// Start parsing the format string `fmt`
+    pub fn new(fmt: &'a str) -> Self {
+        FormatElements {
+            rem: fmt,
+            offset: 0,
+        }
+    }
+
+    /// The offset in the format string of the next element
+    ///
+    ///     use sprintf::parser::FormatElements;
+    ///     let mut elements = FormatElements::new("ab%5d");
+    ///     elements.next();
+    ///     assert_eq!(elements.offset(), 2);
+    ///     elements.next();
+    ///     assert_eq!(elements.offset(), 5);
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
 
-    let mut rem = fmt;
+    /// The elements with their positions
+    pub(crate) fn located(mut self) -> impl Iterator<Item = Result<Located<'a>>> + Clone {
+        iter::from_fn(move || {
+            let offset = self.offset;
+            let elem = self.next()?;
+            Some(elem.map(|elem| Located {
+                elem,
+                position: Position::Known {
+                    offset,
+                    len: self.offset - offset,
+                },
+            }))
+        })
+    }
+}
 
-    while !rem.is_empty() {
-        if let Some((verbatim_prefix, rest)) = rem.split_once('%') {
-            if !verbatim_prefix.is_empty() {
-                res.push(FormatElement::Verbatim(verbatim_prefix));
+impl<'a> Iterator for FormatElements<'a> {
+    type Item = Result<FormatElement<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rem.is_empty() {
+            return None;
+        }
+        let len = self.rem.len();
+        // find the next %
+        let elem = match self.rem.find('%') {
+            Some(0) => match take_conversion_specifier(&self.rem[1..]) {
+                Ok((spec, rest)) => {
+                    self.rem = rest;
+                    Ok(FormatElement::Format(spec))
+                }
+                Err((reason, rest)) => {
+                    let e = ParseError {
+                        offset: self.offset,
+                        len: len - rest.len(),
+                        reason,
+                    };
+                    self.rem = "";
+                    Err(e.into())
+                }
+            },
+            Some(i) => {
+                let (verbatim, rest) = self.rem.split_at(i);
+                self.rem = rest;
+                Ok(FormatElement::Verbatim(verbatim))
             }
-            let (spec, rest) = take_conversion_specifier(rest)?;
-            res.push(FormatElement::Format(spec));
-            rem = rest;
-        } else {
-            res.push(FormatElement::Verbatim(rem));
-            break;
+            None => {
+                let verbatim = self.rem;
+                self.rem = "";
+                Ok(FormatElement::Verbatim(verbatim))
+            }
+        };
+        self.offset += len - self.rem.len();
+        Some(elem)
+    }
+}
+
+/// Parses a byte string to a vector of [FormatElement]s
+///
+/// Like [parse_format_string], but the format string doesn't need to be
+/// valid UTF-8: everything except the conversion specifiers is copied to
+/// the output byte for byte.
+///
+///     use sprintf::parser::{parse_format_bytes, FormatElement};
+///     let parsed = parse_format_bytes(b"caf\xe9 %s").unwrap();
+///     assert_eq!(parsed[0], FormatElement::Verbatim(&b"caf\xe9 "[..]));
+///     assert!(matches!(parsed[1], FormatElement::Format(_)));
+#[cfg(feature = "alloc")]
+pub fn parse_format_bytes(fmt: &[u8]) -> Result<Vec<FormatElement<'_, [u8]>>> {
+    count_args(ByteFormatElements::new(fmt).located())?;
+    ByteFormatElements::new(fmt).collect()
+}
+
+/// Iterator over the [FormatElement]s of a byte format string, parsing
+/// lazily
+///
+/// Like [FormatElements], but for format strings that may not be valid
+/// UTF-8.
+#[derive(Debug, Clone)]
+pub struct ByteFormatElements<'a> {
+    rem: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> ByteFormatElements<'a> {
+    /// Start parsing the format string `fmt`
+    pub fn new(fmt: &'a [u8]) -> Self {
+        ByteFormatElements {
+            rem: fmt,
+            offset: 0,
         }
     }
 
-    Ok(res)
+    /// The elements with their positions
+    #[cfg(feature = "alloc")]
+    pub(crate) fn located(mut self) -> impl Iterator<Item = Result<Located<'a, [u8]>>> + Clone {
+        iter::from_fn(move || {
+            let offset = self.offset;
+            let elem = self.next()?;
+            Some(elem.map(|elem| Located {
+                elem,
+                position: Position::Known {
+                    offset,
+                    len: self.offset - offset,
+                },
+            }))
+        })
+    }
 }
 
-fn take_conversion_specifier(s: &str) -> Result<(ConversionSpecifier, &str)> {
+impl<'a> Iterator for ByteFormatElements<'a> {
+    type Item = Result<FormatElement<'a, [u8]>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rem.is_empty() {
+            return None;
+        }
+        let len = self.rem.len();
+        // find the next %
+        let elem = match self.rem.iter().position(|&b| b == b'%') {
+            Some(0) => {
+                // a conversion specifier is ASCII, so it can be parsed from
+                // the part that is valid UTF-8
+                let rest = &self.rem[1..];
+                let text = match str::from_utf8(rest) {
+                    Ok(text) => text,
+                    Err(e) => str::from_utf8(&rest[..e.valid_up_to()]).unwrap_or_default(),
+                };
+                match take_conversion_specifier(text) {
+                    Ok((spec, text_rest)) => {
+                        self.rem = &rest[text.len() - text_rest.len()..];
+                        Ok(FormatElement::Format(spec))
+                    }
+                    Err((reason, text_rest)) => {
+                        let mut e = ParseError {
+                            offset: self.offset,
+                            len: 1 + text.len() - text_rest.len(),
+                            reason,
+                        };
+                        // the specifier ran into a byte that isn't UTF-8
+                        if reason == ParseErrorReason::DanglingPercent && text.len() < rest.len() {
+                            e.reason = ParseErrorReason::UnknownConversion;
+                            e.len += 1;
+                        }
+                        self.rem = &[];
+                        Err(e.into())
+                    }
+                }
+            }
+            Some(i) => {
+                let (verbatim, rest) = self.rem.split_at(i);
+                self.rem = rest;
+                Ok(FormatElement::Verbatim(verbatim))
+            }
+            None => {
+                let verbatim = self.rem;
+                self.rem = &[];
+                Ok(FormatElement::Verbatim(verbatim))
+            }
+        };
+        self.offset += len - self.rem.len();
+        Some(elem)
+    }
+}
+
+/// Check the use of numbered arguments (`%n$`) in a parsed format string,
+/// and count the arguments it consumes
+///
+/// Numbered and unnumbered conversions must not be mixed, and if numbered
+/// conversions are used, every argument up to the highest number must be
+/// referenced.
+pub(crate) fn count_args<'a, T, I>(fmt: I) -> Result<usize>
+where
+    T: FormatText + ?Sized + 'a,
+    I: Iterator<Item = Result<Located<'a, T>>> + Clone,
+{
+    let mut numbered = None;
+    let mut n_unnumbered = 0;
+    let mut n_numbered = 0;
+    let mut max_arg = 0;
+    let mut max_arg_elem = None;
+    for_each_arg_ref(fmt.clone(), |elem, arg| {
+        if *numbered.get_or_insert(arg.is_some()) != arg.is_some() {
+            return Err(elem.error(ParseErrorReason::MixedArgNumbers));
+        }
+        match arg {
+            Some(n) => {
+                n_numbered += 1;
+                if n > max_arg {
+                    max_arg = n;
+                    max_arg_elem = Some(*elem);
+                }
+            }
+            None => {
+                n_unnumbered += 1;
+            }
+        };
+        Ok(())
+    })?;
+
+    let max_arg_elem = match max_arg_elem {
+        Some(elem) => elem,
+        None => return Ok(n_unnumbered),
+    };
+    // n references can't cover more than the numbers 1 to n, so the first
+    // unreferenced number is at most n + 1
+    if let Some(n) = first_unreferenced(fmt, max_arg.min(n_numbered + 1))? {
+        return Err(max_arg_elem.error(ParseErrorReason::UnusedArgNumber(n)));
+    }
+    Ok(max_arg)
+}
+
+/// The smallest argument number from 1 to `limit` that isn't referenced by
+/// `fmt`
+///
+/// Up to 4096 numbers are checked in a single pass, without allocating.
+/// More are checked with an allocation, or without an allocator, in blocks
+/// of 4096 with a pass over `fmt` for each block.
+fn first_unreferenced<'a, T, I>(fmt: I, limit: usize) -> Result<Option<usize>>
+where
+    T: ?Sized + 'a,
+    I: Iterator<Item = Result<Located<'a, T>>> + Clone,
+{
+    const BLOCK: usize = 64 * 64;
+    #[cfg(feature = "alloc")]
+    if limit > BLOCK {
+        let mut referenced = alloc::vec![false; limit];
+        for_each_arg_ref(fmt, |_, arg| {
+            match arg {
+                Some(n) if n <= limit => referenced[n - 1] = true,
+                _ => {}
+            }
+            Ok(())
+        })?;
+        return Ok(referenced.iter().position(|&r| !r).map(|i| i + 1));
+    }
+    let mut start = 1;
+    while start <= limit {
+        let len = BLOCK.min(limit - start + 1);
+        let mut referenced = [0_u64; BLOCK / 64];
+        for_each_arg_ref(fmt.clone(), |_, arg| {
+            match arg {
+                Some(n) if n >= start && n - start < len => {
+                    referenced[(n - start) / 64] |= 1 << ((n - start) % 64);
+                }
+                _ => {}
+            }
+            Ok(())
+        })?;
+        if let Some(i) = (0..len).find(|i| referenced[i / 64] & (1 << (i % 64)) == 0) {
+            return Ok(Some(start + i));
+        }
+        start += len;
+    }
+    Ok(None)
+}
+
+/// Call `f` for every argument referenced by `fmt`, with the element
+/// referencing it and the argument number if it is numbered.
+fn for_each_arg_ref<'a, T: ?Sized + 'a>(
+    fmt: impl Iterator<Item = Result<Located<'a, T>>>,
+    mut f: impl FnMut(&Located<'a, T>, Option<usize>) -> Result<()>,
+) -> Result<()> {
+    for elem in fmt {
+        let elem = elem?;
+        if let FormatElement::Format(spec) = elem.elem {
+            if spec.conversion_type == ConversionType::PercentSign {
+                continue;
+            }
+            for param in [spec.width, spec.precision] {
+                match param {
+                    NumericParam::FromArgument => f(&elem, None)?,
+                    NumericParam::FromNumberedArgument(n) => f(&elem, Some(n))?,
+                    NumericParam::Literal(_) => {}
+                }
+            }
+            f(&elem, spec.arg_number)?;
+        }
+    }
+    Ok(())
+}
+
+fn take_conversion_specifier(s: &str) -> SpecResult<'_, ConversionSpecifier> {
     let mut spec = ConversionSpecifier {
         alt_form: false,
         zero_pad: false,
         left_adj: false,
         space_sign: false,
         force_sign: false,
+        thousands_grouping: false,
+        locale_digits: false,
         width: NumericParam::Literal(0),
-        precision: NumericParam::FromArgument, // Placeholder - must not be returned!
-        // ignore length modifier
+        precision: NumericParam::Literal(-1),
+        length_modifier: None,
         conversion_type: ConversionType::DecInt,
+        conversion_char: '%',
+        arg_number: None,
     };
 
-    let mut s = s;
+    // parse argument number
+    let (arg_number, mut s) = take_arg_number(s)?;
+    spec.arg_number = arg_number;
 
     // parse flags
     loop {
@@ -156,6 +785,12 @@ fn take_conversion_specifier(s: &str) -> Result<(ConversionSpecifier, &str)> {
             Some('+') => {
                 spec.force_sign = true;
             }
+            Some('\'') => {
+                spec.thousands_grouping = true;
+            }
+            Some('I') => {
+                spec.locale_digits = true;
+            }
             _ => {
                 break;
             }
@@ -163,80 +798,124 @@ fn take_conversion_specifier(s: &str) -> Result<(ConversionSpecifier, &str)> {
         s = &s[1..];
     }
     // parse width
-    let (w, mut s) = take_numeric_param(s);
+    let (w, mut s) = take_numeric_param(s)?;
     spec.width = w;
     // parse precision
     if matches!(s.chars().next(), Some('.')) {
         s = &s[1..];
-        let (p, s2) = take_numeric_param(s);
+        let (p, s2) = take_numeric_param(s)?;
         spec.precision = p;
         s = s2;
     }
     // check length specifier
-    for len_spec in ["hh", "h", "ll", "l", "q", "L", "j", "z", "Z", "t"] {
-        if s.starts_with(len_spec) {
-            s = s.strip_prefix(len_spec).ok_or(PrintfError::ParseError)?;
-            break; // only allow one length specifier
+    if let Some(s2) = s.strip_prefix('w') {
+        // C23 wN or wfN (with N = 128 as an extension, for i128 and u128)
+        let (fast, s2) = match s2.strip_prefix('f') {
+            Some(s2) => (true, s2),
+            None => (false, s2),
+        };
+        let (bits, s2) = take_decimal(s2);
+        let bits = match bits {
+            Some(bits @ (8 | 16 | 32 | 64 | 128)) => bits as u32,
+            _ => return Err((ParseErrorReason::InvalidLengthModifier, s2)),
+        };
+        spec.length_modifier = Some(if fast {
+            LengthModifier::Fast(bits)
+        } else {
+            LengthModifier::Exact(bits)
+        });
+        s = s2;
+    } else {
+        for (len_str, len_spec) in LENGTH_MODIFIERS {
+            if let Some(s2) = s.strip_prefix(len_str) {
+                spec.length_modifier = Some(len_spec);
+                s = s2;
+                break; // only allow one length specifier
+            }
+        }
+    }
+    if spec.length_modifier.is_some() {
+        if let Some(s2) = LENGTH_MODIFIERS
+            .iter()
+            .map(|(len_str, _)| *len_str)
+            .chain(["w"])
+            .find_map(|len_str| s.strip_prefix(len_str))
+        {
+            return Err((ParseErrorReason::DuplicateLengthModifier, s2));
         }
     }
     // parse conversion type
-    spec.conversion_type = match s.chars().next() {
-        Some('i') | Some('d') | Some('u') => ConversionType::DecInt,
-        Some('o') => ConversionType::OctInt,
-        Some('x') => ConversionType::HexIntLower,
-        Some('X') => ConversionType::HexIntUpper,
-        Some('e') => ConversionType::SciFloatLower,
-        Some('E') => ConversionType::SciFloatUpper,
-        Some('f') => ConversionType::DecFloatLower,
-        Some('F') => ConversionType::DecFloatUpper,
-        Some('g') => ConversionType::CompactFloatLower,
-        Some('G') => ConversionType::CompactFloatUpper,
-        Some('c') | Some('C') => ConversionType::Char,
-        Some('s') | Some('S') => ConversionType::String,
-        Some('p') => {
-            spec.alt_form = true;
-            ConversionType::HexIntLower
-        }
-        Some('%') => ConversionType::PercentSign,
+    let c = s
+        .chars()
+        .next()
+        .ok_or((ParseErrorReason::DanglingPercent, s))?;
+    spec.conversion_char = c;
+    spec.conversion_type = match c {
+        'i' | 'd' => ConversionType::DecInt,
+        'u' => ConversionType::UnsignedInt,
+        'o' => ConversionType::OctInt,
+        'x' => ConversionType::HexIntLower,
+        'X' => ConversionType::HexIntUpper,
+        'b' => ConversionType::BinIntLower,
+        'B' => ConversionType::BinIntUpper,
+        'e' => ConversionType::SciFloatLower,
+        'E' => ConversionType::SciFloatUpper,
+        'f' => ConversionType::DecFloatLower,
+        'F' => ConversionType::DecFloatUpper,
+        'g' => ConversionType::CompactFloatLower,
+        'G' => ConversionType::CompactFloatUpper,
+        'a' => ConversionType::HexFloatLower,
+        'A' => ConversionType::HexFloatUpper,
+        'c' | 'C' => ConversionType::Char,
+        's' | 'S' => ConversionType::String,
+        'p' => ConversionType::Pointer,
+        'n' => ConversionType::Count,
+        '%' => ConversionType::PercentSign,
         _ => {
-            return Err(PrintfError::ParseError);
+            return Err((ParseErrorReason::UnknownConversion, &s[c.len_utf8()..]));
         }
     };
 
-    if spec.precision == NumericParam::FromArgument {
-        // If precision is not specified, set to default value
-        let p = if spec.conversion_type == ConversionType::String {
-            // Default to max limit (aka no limit) for strings
-            i32::MAX
-        } else {
-            // Default to 6 for all other types
-            6
+    Ok((spec, &s[c.len_utf8()..]))
+}
+
+fn take_numeric_param(s: &str) -> SpecResult<'_, NumericParam> {
+    if let Some(s) = s.strip_prefix('*') {
+        let (arg_number, s) = take_arg_number(s)?;
+        let param = match arg_number {
+            Some(n) => NumericParam::FromNumberedArgument(n),
+            None => NumericParam::FromArgument,
         };
-        spec.precision = NumericParam::Literal(p);
+        Ok((param, s))
+    } else {
+        let (w, s) = take_decimal(s);
+        Ok((NumericParam::Literal(w.unwrap_or(0)), s))
     }
-
-    Ok((spec, &s[1..]))
 }
 
-fn take_numeric_param(s: &str) -> (NumericParam, &str) {
-    match s.chars().next() {
-        Some('*') => (NumericParam::FromArgument, &s[1..]),
-        Some(digit) if ('0'..='9').contains(&digit) => {
-            let mut s = s;
-            let mut w = 0;
-            loop {
-                match s.chars().next() {
-                    Some(digit) if ('0'..='9').contains(&digit) => {
-                        w = 10 * w + (digit as i32 - '0' as i32);
-                    }
-                    _ => {
-                        break;
-                    }
-                }
-                s = &s[1..];
+/// Parse an argument number `n$`, if present
+fn take_arg_number(s: &str) -> SpecResult<'_, Option<usize>> {
+    match take_decimal(s) {
+        (Some(n), rest) if rest.starts_with('$') => {
+            if n <= 0 {
+                Err((ParseErrorReason::BadArgNumber, &rest[1..]))
+            } else {
+                Ok((Some(n as usize), &rest[1..]))
             }
-            (NumericParam::Literal(w), s)
         }
-        _ => (NumericParam::Literal(0), s),
+        _ => Ok((None, s)),
+    }
+}
+
+/// Parse a (possibly empty) string of decimal digits
+fn take_decimal(s: &str) -> (Option<i32>, &str) {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    if end == 0 {
+        return (None, s);
+    }
+    let mut n: i32 = 0;
+    for digit in s[..end].bytes() {
+        n = n.saturating_mul(10).saturating_add((digit - b'0') as i32);
     }
+    (Some(n), &s[end..])
 }
@@ -0,0 +1,351 @@
+//! Format strings checked once against the types of their arguments
+
+#[cfg(target_has_atomic = "ptr")]
+use alloc::sync::Arc;
+use alloc::{
+    borrow::{Cow, ToOwned},
+    boxed::Box,
+    rc::Rc,
+    string::String,
+};
+use core::cell::Cell;
+use core::fmt;
+use core::marker::PhantomData;
+use core::num::{
+    NonZeroI128, NonZeroI16, NonZeroI32, NonZeroI64, NonZeroI8, NonZeroIsize, NonZeroU128,
+    NonZeroU16, NonZeroU32, NonZeroU64, NonZeroU8, NonZeroUsize, Saturating, Wrapping,
+};
+#[cfg(target_has_atomic = "ptr")]
+use core::sync::atomic::AtomicUsize;
+
+use crate::parser::ConversionType;
+use crate::{ArgContext, ArgRole, CompiledFormat, Printf, PrintfError, Result};
+
+/// A [Printf] type whose conversions are known from the type alone, so a
+/// [Format] can check them before it is given any values
+///
+/// Only conversions that can't fail for any value of the type are accepted:
+/// a `u8` can't be formatted with `%c`, since C only allows that for ASCII.
+/// [Format::render] panics if an implementation breaks this rule.
+pub trait FormatArg: Printf {
+    /// Can every value of this type be formatted with `conversion`?
+    fn accepts(conversion: ConversionType) -> bool;
+
+    /// Can this type be used as a width or precision given by `*`?
+    fn is_width() -> bool {
+        false
+    }
+}
+
+fn is_int_conversion(conversion: ConversionType) -> bool {
+    matches!(
+        conversion,
+        ConversionType::DecInt
+            | ConversionType::UnsignedInt
+            | ConversionType::OctInt
+            | ConversionType::HexIntLower
+            | ConversionType::HexIntUpper
+            | ConversionType::BinIntLower
+            | ConversionType::BinIntUpper
+            | ConversionType::Pointer
+    )
+}
+
+macro_rules! impl_format_arg {
+    ($is_width:literal; $accepts:expr; $($t:ty),*) => {
+        $(
+            impl FormatArg for $t {
+                fn accepts(conversion: ConversionType) -> bool {
+                    $accepts(conversion)
+                }
+                fn is_width() -> bool {
+                    $is_width
+                }
+            }
+        )*
+    };
+}
+
+// only the types that always fit in an `int` can be widths
+impl_format_arg!(true; is_int_conversion; i8, u8, i16, u16, i32, bool);
+impl_format_arg!(
+    true;
+    is_int_conversion;
+    NonZeroI8,
+    NonZeroU8,
+    NonZeroI16,
+    NonZeroU16,
+    NonZeroI32
+);
+impl_format_arg!(false; is_int_conversion; u32, i64, u64, i128, u128, isize, usize);
+impl_format_arg!(
+    false;
+    is_int_conversion;
+    NonZeroU32,
+    NonZeroI64,
+    NonZeroU64,
+    NonZeroI128,
+    NonZeroU128,
+    NonZeroIsize,
+    NonZeroUsize
+);
+impl_format_arg!(
+    false;
+    |conversion| matches!(
+        conversion,
+        ConversionType::DecFloatLower
+            | ConversionType::DecFloatUpper
+            | ConversionType::SciFloatLower
+            | ConversionType::SciFloatUpper
+            | ConversionType::CompactFloatLower
+            | ConversionType::CompactFloatUpper
+            | ConversionType::HexFloatLower
+            | ConversionType::HexFloatUpper
+    );
+    f32,
+    f64
+);
+impl_format_arg!(false; |conversion| conversion == ConversionType::Char; char);
+impl_format_arg!(false; |conversion| conversion == ConversionType::String; str, String);
+impl_format_arg!(
+    false;
+    |conversion| conversion == ConversionType::Count;
+    Cell<i8>,
+    Cell<u8>,
+    Cell<i16>,
+    Cell<u16>,
+    Cell<i32>,
+    Cell<u32>,
+    Cell<i64>,
+    Cell<u64>,
+    Cell<isize>,
+    Cell<usize>
+);
+#[cfg(target_has_atomic = "ptr")]
+impl_format_arg!(false; |conversion| conversion == ConversionType::Count; AtomicUsize);
+
+impl<T> FormatArg for *const T {
+    fn accepts(conversion: ConversionType) -> bool {
+        is_int_conversion(conversion)
+    }
+}
+
+impl<T> FormatArg for *mut T {
+    fn accepts(conversion: ConversionType) -> bool {
+        is_int_conversion(conversion)
+    }
+}
+
+/// Forwarded the same way as the [checked](crate::checked) traits
+macro_rules! impl_format_arg_forwarding {
+    ($($t:ty $(where T: $bound:path)?;)*) => {
+        $(
+            impl<'a, T: FormatArg + ?Sized> FormatArg for $t $(where T: $bound)? {
+                fn accepts(conversion: ConversionType) -> bool {
+                    T::accepts(conversion)
+                }
+                fn is_width() -> bool {
+                    T::is_width()
+                }
+            }
+        )*
+    };
+}
+
+impl_format_arg_forwarding!(&'a T; &'a mut T; Box<T>; Rc<T>; Cow<'a, T> where T: ToOwned;);
+#[cfg(target_has_atomic = "ptr")]
+impl_format_arg_forwarding!(Arc<T>;);
+
+impl<T: FormatArg> FormatArg for Wrapping<T> {
+    fn accepts(conversion: ConversionType) -> bool {
+        T::accepts(conversion)
+    }
+    fn is_width() -> bool {
+        T::is_width()
+    }
+}
+
+impl<T: FormatArg> FormatArg for Saturating<T> {
+    fn accepts(conversion: ConversionType) -> bool {
+        T::accepts(conversion)
+    }
+    fn is_width() -> bool {
+        T::is_width()
+    }
+}
+
+/// `None` can only be formatted with `%s` and `%p`
+impl<T: FormatArg> FormatArg for Option<T> {
+    fn accepts(conversion: ConversionType) -> bool {
+        matches!(conversion, ConversionType::String | ConversionType::Pointer)
+            && T::accepts(conversion)
+    }
+}
+
+/// The arguments of a [Format]: a tuple of [FormatArg] types
+pub trait ArgList {
+    /// The number of arguments
+    const LEN: usize;
+
+    /// Can the argument with the given (zero-based) index be formatted with
+    /// `conversion`?
+    fn accepts(index: usize, conversion: ConversionType) -> bool;
+
+    /// Can the argument with the given (zero-based) index be used as a width
+    /// or precision given by `*`?
+    fn is_width(index: usize) -> bool;
+
+    /// Call `f` with the arguments, as for [vsprintf](crate::vsprintf)
+    fn with_args<R>(&self, f: impl FnOnce(&[&dyn Printf]) -> R) -> R;
+}
+
+impl ArgList for () {
+    const LEN: usize = 0;
+
+    fn accepts(_index: usize, _conversion: ConversionType) -> bool {
+        false
+    }
+    fn is_width(_index: usize) -> bool {
+        false
+    }
+    fn with_args<R>(&self, f: impl FnOnce(&[&dyn Printf]) -> R) -> R {
+        f(&[])
+    }
+}
+
+macro_rules! impl_arg_list {
+    ($len:literal; $($t:ident $i:tt),*) => {
+        impl<$($t: FormatArg),*> ArgList for ($($t,)*) {
+            const LEN: usize = $len;
+
+            fn accepts(index: usize, conversion: ConversionType) -> bool {
+                match index {
+                    $($i => $t::accepts(conversion),)*
+                    _ => false,
+                }
+            }
+            fn is_width(index: usize) -> bool {
+                match index {
+                    $($i => $t::is_width(),)*
+                    _ => false,
+                }
+            }
+            fn with_args<R>(&self, f: impl FnOnce(&[&dyn Printf]) -> R) -> R {
+                f(&[$(&self.$i as &dyn Printf),*])
+            }
+        }
+    };
+}
+
+impl_arg_list!(1; A 0);
+impl_arg_list!(2; A 0, B 1);
+impl_arg_list!(3; A 0, B 1, C 2);
+impl_arg_list!(4; A 0, B 1, C 2, D 3);
+impl_arg_list!(5; A 0, B 1, C 2, D 3, E 4);
+impl_arg_list!(6; A 0, B 1, C 2, D 3, E 4, F 5);
+impl_arg_list!(7; A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_arg_list!(8; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+impl_arg_list!(9; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8);
+impl_arg_list!(10; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9);
+impl_arg_list!(11; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10);
+impl_arg_list!(12; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10, L 11);
+
+/// A format string, parsed and checked against the types of its arguments
+///
+/// All errors are found by [Format::new], so formatting with it can't fail.
+/// Use it for format strings that are only known at runtime but used many
+/// times.
+///
+///     use sprintf::Format;
+///     let format = Format::<(&str, f64)>::new("%-6s%6.2f").unwrap();
+///     assert_eq!(format.render(("pi", 3.14159)), "pi      3.14");
+///     assert_eq!(format.render(("e", 2.71828)), "e       2.72");
+///
+///     // the second argument isn't an integer
+///     assert!(Format::<(&str, f64)>::new("%s %d").is_err());
+///
+/// The arguments are formatted with the default [FormatOptions](crate::FormatOptions).
+pub struct Format<A> {
+    format: CompiledFormat,
+    args: PhantomData<fn() -> A>,
+}
+
+impl<A: ArgList> Format<A> {
+    /// Parse `format` and check it against the argument types `A`
+    ///
+    /// Fails with [PrintfError::ParseError] if `format` is invalid, with
+    /// [PrintfError::NotEnoughArgs] or [PrintfError::TooManyArgs] if it
+    /// doesn't take `A::LEN` arguments, and with [PrintfError::WrongType] if
+    /// an argument type doesn't accept its conversion.
+    pub fn new(format: &str) -> Result<Self> {
+        Self::from_compiled(CompiledFormat::new(format)?)
+    }
+
+    /// Check an already parsed format string against the argument types `A`
+    ///
+    /// Fails like [Format::new].
+    pub fn from_compiled(format: CompiledFormat) -> Result<Self> {
+        if A::LEN > format.arg_count() {
+            return Err(PrintfError::TooManyArgs);
+        }
+        for arg_use in format.arg_uses() {
+            let context = ArgContext {
+                index: arg_use.index,
+                spec: arg_use.spec,
+            };
+            if arg_use.index >= A::LEN {
+                return Err(PrintfError::NotEnoughArgs(context));
+            }
+            let ok = match arg_use.role {
+                ArgRole::Width | ArgRole::Precision => A::is_width(arg_use.index),
+                ArgRole::Value => A::accepts(arg_use.index, arg_use.spec.conversion_type),
+            };
+            if !ok {
+                return Err(PrintfError::WrongType(Some(context)));
+            }
+        }
+        Ok(Format {
+            format,
+            args: PhantomData,
+        })
+    }
+
+    /// Format the arguments
+    pub fn render(&self, args: A) -> String {
+        let mut res = String::new();
+        self.write(&mut res, args)
+            .expect("a formatter error when writing to a String");
+        res
+    }
+
+    /// Format the arguments, writing the result to a [fmt::Write]
+    ///
+    /// Only fails if `out` does.
+    pub fn write<W: fmt::Write>(&self, out: &mut W, args: A) -> fmt::Result {
+        args.with_args(|args| match self.format.vwritef(out, args) {
+            Ok(()) => Ok(()),
+            Err(PrintfError::WriteError) => Err(fmt::Error),
+            Err(e) => panic!("formatting checked arguments failed: {}", e),
+        })
+    }
+
+    /// The parsed format string
+    pub fn compiled(&self) -> &CompiledFormat {
+        &self.format
+    }
+}
+
+impl<A> Clone for Format<A> {
+    fn clone(&self) -> Self {
+        Format {
+            format: self.format.clone(),
+            args: PhantomData,
+        }
+    }
+}
+
+impl<A> fmt::Debug for Format<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Format").field(&self.format).finish()
+    }
+}
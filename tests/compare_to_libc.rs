@@ -1,12 +1,14 @@
 // The libc crate on Windows doesn't have snprintf
 #![cfg(not(windows))]
 
+use std::cell::Cell;
 use std::convert::{TryFrom, TryInto};
 use std::ffi::CString;
 use std::mem::size_of;
-use std::os::raw::c_char;
+use std::os::raw::{c_char, c_int};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use libc::snprintf;
+use libc::{snprintf, wchar_t};
 
 use sprintf::*;
 
@@ -29,6 +31,40 @@ fn check_fmt<T: Printf>(fmt: &str, arg: T) {
     assert_eq!(our_result, c_result);
 }
 
+/// Compare our output to C's: `c_printf` calls `snprintf` (or `swprintf`)
+/// with a buffer and its length
+fn check_c_output<C: Copy + Default + PartialEq + std::fmt::Debug>(
+    ours: &[C],
+    c_printf: impl FnOnce(*mut C, usize) -> c_int,
+) {
+    let mut buf = vec![C::default(); ours.len() + 1];
+    let clen: usize = c_printf(buf.as_mut_ptr(), buf.len()).try_into().unwrap();
+    buf.truncate(clen); // drop the final '\0', etc.
+    assert_eq!(ours, &buf[..]);
+}
+
+/// Like [check_fmt], converting the argument to the C type given by the
+/// length modifier
+fn check_fmt_c<T: Printf>(fmt: &str, arg: T) {
+    let options = FormatOptions {
+        apply_length_modifiers: true,
+        ..Default::default()
+    };
+    let ours = options.vsprintf(fmt, &[&arg]).unwrap();
+    let cfmt = CString::new(fmt).unwrap();
+    check_c_output(ours.as_bytes(), |buf, len| unsafe {
+        snprintf(buf.cast(), len, cfmt.as_ptr(), arg)
+    });
+}
+
+fn check_fmt_2<T: Printf, U: Printf>(fmt: &str, arg1: T, arg2: U) {
+    let ours = sprintf!(fmt, arg1, arg2).unwrap();
+    let cfmt = CString::new(fmt).unwrap();
+    check_c_output(ours.as_bytes(), |buf, len| unsafe {
+        snprintf(buf.cast(), len, cfmt.as_ptr(), arg1, arg2)
+    });
+}
+
 fn check_fmt_s(fmt: &str, arg: &str) {
     let our_result = sprintf!(fmt, arg).unwrap();
     let mut buf = vec![0_u8; our_result.len() + 1];
@@ -49,6 +85,46 @@ fn check_fmt_s(fmt: &str, arg: &str) {
     assert_eq!(our_result, c_result);
 }
 
+fn check_fmt_bytes(fmt: &[u8], arg: &[u8]) {
+    let ours = vsprintf_bytes(fmt, &[&arg]).unwrap();
+    let cfmt = CString::new(fmt).unwrap();
+    let carg = CString::new(arg).unwrap();
+    check_c_output(&ours, |buf, len| unsafe {
+        snprintf(buf.cast(), len, cfmt.as_ptr(), carg.as_ptr())
+    });
+}
+
+extern "C" {
+    fn swprintf(s: *mut wchar_t, n: usize, format: *const wchar_t, ...) -> c_int;
+}
+
+fn wide(s: &str) -> Vec<u32> {
+    s.chars().map(u32::from).chain([0]).collect()
+}
+
+fn check_fmt_ls(fmt: &str, arg: &str) {
+    let warg = wide(arg);
+    let utf16: Vec<u16> = arg.encode_utf16().collect();
+    let ours = sprintf!(fmt, &warg[..warg.len() - 1]).unwrap();
+    assert_eq!(sprintf!(fmt, &utf16[..]).unwrap(), ours);
+    let cfmt = CString::new(fmt).unwrap();
+    check_c_output(ours.as_bytes(), |buf, len| unsafe {
+        snprintf(buf.cast(), len, cfmt.as_ptr(), warg.as_ptr())
+    });
+}
+
+fn check_fmt_w<T: Printf>(fmt: &str, arg: T) {
+    let wfmt = wide(fmt);
+    let ours: Vec<wchar_t> = vswprintf_utf32(&wfmt[..wfmt.len() - 1], &[&arg])
+        .unwrap()
+        .iter()
+        .map(|&c| c as wchar_t)
+        .collect();
+    check_c_output(&ours, |buf, len| unsafe {
+        swprintf(buf, len, wfmt.as_ptr().cast(), arg)
+    });
+}
+
 #[test]
 fn test_int() {
     check_fmt("%d", 12);
@@ -68,6 +144,261 @@ fn test_int() {
     check_fmt("%ld", 48_i64);
     check_fmt("%-8hd", -12_i16);
     check_fmt("%llx", 0x0123456789abcdef_u64);
+    check_fmt("%u", -1);
+    check_fmt("%+u", 42);
+    check_fmt("%lu", -4_i64);
+    check_fmt("%hu", -12_i16);
+    check_fmt("%hhu", -1_i8);
+}
+
+#[test]
+fn test_int128() {
+    // C has no 128-bit conversions without `w128`, so compare to Rust
+    for val in [
+        0,
+        1,
+        -1,
+        i128::MIN,
+        i128::MAX,
+        0x0123456789abcdef0123456789abcdef,
+    ] {
+        assert_eq!(sprintf!("%d", val).unwrap(), format!("{}", val));
+        assert_eq!(sprintf!("%+045d", val).unwrap(), format!("{:+045}", val));
+        assert_eq!(sprintf!("%x", val).unwrap(), format!("{:x}", val as u128));
+        assert_eq!(sprintf!("%o", val).unwrap(), format!("{:o}", val as u128));
+        assert_eq!(sprintf!("%B", val).unwrap(), format!("{:b}", val as u128));
+    }
+    assert_eq!(
+        sprintf!("%u", u128::MAX).unwrap(),
+        "340282366920938463463374607431768211455"
+    );
+    let options = FormatOptions {
+        apply_length_modifiers: true,
+        strict: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        options.vsprintf("%w128x|%d", &[&-1_i128, &true]).unwrap(),
+        "ffffffffffffffffffffffffffffffff|1"
+    );
+    assert!(matches!(
+        options.vsprintf("%lld", &[&-1_i128]),
+        Err(PrintfError::WrongType(_))
+    ));
+}
+
+#[test]
+fn test_int_wrappers() {
+    use std::num::{NonZeroI64, NonZeroU32, NonZeroU8, Saturating, Wrapping};
+
+    check_fmt("%d", true);
+    check_fmt("%u", true);
+    check_fmt("%5d", false);
+    assert_eq!(
+        sprintf!(
+            "%d %x %c %#o",
+            NonZeroI64::new(-5).unwrap(),
+            NonZeroU32::new(255).unwrap(),
+            NonZeroU8::new(b'A').unwrap(),
+            NonZeroU32::new(8).unwrap()
+        )
+        .unwrap(),
+        "-5 ff A 010"
+    );
+    assert_eq!(
+        sprintf!(
+            "%d %x %*d",
+            Wrapping(-3_i8),
+            Saturating(u8::MAX),
+            Wrapping(3),
+            1
+        )
+        .unwrap(),
+        "-3 ff   1"
+    );
+    let options = FormatOptions {
+        apply_length_modifiers: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        options.vsprintf("%hhu", &[&Wrapping(-1_i64)]).unwrap(),
+        "255"
+    );
+}
+
+#[test]
+fn test_length_modifiers() {
+    for fmt in [
+        "%hhd", "%hhu", "%hhx", "%hd", "%hu", "%#ho", "%d", "%u", "%x",
+    ] {
+        for val in [
+            0,
+            1,
+            -1,
+            127,
+            200,
+            300,
+            -300,
+            70000,
+            -70000,
+            i32::MAX,
+            i32::MIN,
+        ] {
+            check_fmt_c(fmt, val);
+        }
+    }
+    for fmt in ["%ld", "%lu", "%lld", "%llx", "%jd", "%zu", "%zx", "%td"] {
+        for val in [0_i64, -1, 1 << 40, i64::MAX, i64::MIN] {
+            check_fmt_c(fmt, val);
+        }
+    }
+
+    // %c takes an int converted to unsigned char
+    for val in [65, 321, -191, 0x7a] {
+        check_fmt_c("%c", val);
+        check_fmt_c("%-3c|", val);
+    }
+
+    // wider arguments are converted as well
+    let options = FormatOptions {
+        apply_length_modifiers: true,
+        ..Default::default()
+    };
+    let fmt = |fmt: &str, arg: &dyn Printf| options.vsprintf(fmt, &[arg]).unwrap();
+    assert_eq!(fmt("%hhx", &0x1234_5678_u64), "78");
+    assert_eq!(fmt("%d", &(1_i64 << 32)), "0");
+    assert_eq!(fmt("%lu", &-1_i8), "18446744073709551615");
+    assert_eq!(fmt("%hd", &u16::MAX), "-1");
+    // other conversions are unaffected
+    assert_eq!(fmt("%hhs", &"str"), "str");
+    assert_eq!(fmt("%hhc", &'\u{263a}'), "\u{263a}");
+    // without the option, the argument type is used
+    assert_eq!(sprintf!("%hhx", 300).unwrap(), "12c");
+}
+
+#[test]
+fn test_strict() {
+    let options = FormatOptions {
+        strict: true,
+        ..Default::default()
+    };
+    let fmt = |fmt: &str, args: &[&dyn Printf]| options.vsprintf(fmt, args);
+    assert_eq!(
+        fmt(
+            "%d %hhu %hx %lld %zu",
+            &[&1, &2_u8, &3_i16, &4_i64, &5_usize]
+        )
+        .unwrap(),
+        "1 2 3 4 5"
+    );
+    let null = std::ptr::null::<u8>();
+    assert_eq!(
+        fmt(
+            "%c%lc%s %f %.*f %p",
+            &[&'a', &'b', &"c", &1.0_f32, &1, &2.0, &null]
+        )
+        .unwrap(),
+        "abc 1.000000 2.0 (nil)"
+    );
+    assert!(matches!(
+        fmt("%lld", &[&1_i8]),
+        Err(PrintfError::WrongType(_))
+    ));
+    assert!(matches!(
+        fmt("%hd", &[&1_i64]),
+        Err(PrintfError::WrongType(_))
+    ));
+    assert!(matches!(fmt("%d", &[&1.0]), Err(PrintfError::WrongType(_))));
+    assert!(matches!(
+        fmt("%Lf", &[&1.0]),
+        Err(PrintfError::WrongType(_))
+    ));
+    assert!(matches!(fmt("%s", &[&'s']), Err(PrintfError::WrongType(_))));
+    assert!(matches!(
+        fmt("%*d", &[&1_i64, &1]),
+        Err(PrintfError::WrongType(_))
+    ));
+    // integers narrower than int are promoted to int, as in C
+    assert_eq!(
+        fmt(
+            "%d %i %x %c %c %*d",
+            &[&-1_i8, &2_i16, &3_u16, &'c', &100, &3_u8, &6]
+        )
+        .unwrap(),
+        "-1 2 3 c d   6"
+    );
+    assert!(matches!(
+        fmt("%c", &[&1_i64]),
+        Err(PrintfError::WrongType(_))
+    ));
+    assert!(matches!(
+        fmt("%hhd", &[&1_i32]),
+        Err(PrintfError::WrongType(_))
+    ));
+
+    // nothing is written if any argument has the wrong type
+    let mut s = String::new();
+    let res = options.vwritef(&mut s, "%d %d", &[&1, &2_i64]);
+    assert!(matches!(res, Err(PrintfError::WrongType(_))));
+    assert_eq!(s, "");
+}
+
+#[test]
+fn test_int_precision() {
+    for fmt in [
+        "%.5d", "%.0d", "%.d", "%8.5d", "%-8.5d|", "%08.5d", "%08d", "%-08d|", "%+.3d", "% .3d",
+        "%+08d", "% 08d", "%.5x", "%#.5x", "%#x", "%#08x", "%#.0x", "%#o", "%#.5o", "%#.0o",
+        "%#8o", "%#08o", "%.0o", "%.3o", "%.0X", "%#.3X", "%5.0d|", "%5.0x|",
+    ] {
+        for val in [0, 1, 8, 42, -42, 255, 0o777, i32::MAX, i32::MIN] {
+            check_fmt(fmt, val);
+        }
+    }
+    check_fmt("%.20lld", i64::MIN);
+    check_fmt("%#.25llo", u64::MAX);
+}
+
+#[test]
+fn test_c23() {
+    // binary conversions need glibc 2.35
+    for fmt in [
+        "%b", "%B", "%#b", "%#B", "%#010b", "%-#10b|", "%.5b", "%#.0b", "%+b", "%12.9b",
+    ] {
+        for val in [0, 1, 5, 255, -1, i32::MIN] {
+            check_fmt(fmt, val);
+        }
+    }
+    check_fmt("%llb", u64::MAX);
+    check_fmt("%hhb", 7_u8);
+
+    // wN and wfN length modifiers need glibc 2.39
+    assert_eq!(
+        sprintf!("%w32d|%w8x|%w64u", -7, 255_u8, u64::MAX).unwrap(),
+        "-7|ff|18446744073709551615"
+    );
+    assert_eq!(sprintf!("%wf16d", 1).unwrap(), "1");
+    assert!(matches!(
+        sprintf!("%w12d", 1),
+        Err(PrintfError::ParseError(_))
+    ));
+    assert!(matches!(
+        sprintf!("%wd", 1),
+        Err(PrintfError::ParseError(_))
+    ));
+    let options = FormatOptions {
+        apply_length_modifiers: true,
+        ..Default::default()
+    };
+    assert_eq!(options.vsprintf("%w8d", &[&300]).unwrap(), "44");
+    assert_eq!(
+        options.vsprintf("%#w16B", &[&-1]).unwrap(),
+        "0B1111111111111111"
+    );
+    assert_eq!(options.vsprintf("%wf8u", &[&-1]).unwrap(), "255");
+    assert_eq!(
+        options.vsprintf("%wf64x", &[&-1_i64]).unwrap(),
+        "ffffffffffffffff"
+    );
 }
 
 #[test]
@@ -100,6 +431,9 @@ fn test_float() {
     check_fmt("%.1f", 2.599);
     check_fmt("%.1e", 2.599);
     check_fmt("%.1g", 2.599);
+    // padded numbers too long to be measured in one go
+    check_fmt("%1200.1100f", -2.599);
+    check_fmt("%-300.250e|", 2.599);
 
     // MacOS libc behaves differently from glibc for nan. glibc is the reference implementation.
     if cfg!(target_env = "gnu") {
@@ -111,6 +445,153 @@ fn test_float() {
     }
 }
 
+#[test]
+fn test_io_write() {
+    /// Counts the calls to `write`
+    struct Calls(Vec<u8>, usize);
+    impl std::io::Write for Calls {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.1 += 1;
+            self.0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let mut out = Calls(Vec::new(), 0);
+    let args: [&dyn Printf; 3] = [&"name", &-1.5, &42];
+    vwritef_io(&mut out, "%-10s|%10.3f|%#8x|%%\n", &args).unwrap();
+    assert_eq!(out.0, b"name      |    -1.500|    0x2a|%\n");
+    assert_eq!(out.1, 1);
+    // output longer than the buffer
+    let mut out = Calls(Vec::new(), 0);
+    vwritef_io(&mut out, "%2000d|%s", &[&1, &"end"]).unwrap();
+    assert_eq!(out.0, sprintf!("%2000d|%s", 1, "end").unwrap().as_bytes());
+    // what was formatted before an error is written
+    let mut out = Calls(Vec::new(), 0);
+    let err = vwritef_io(&mut out, "%s %d", &[&"ok", &"no"]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(out.0, b"ok ");
+}
+
+#[test]
+fn test_float_exact() {
+    for fmt in [
+        "%f", "%.0f", "%#.0f", "%.1f", "%.2f", "%.25f", "%.60f", "%.1100f", "%e", "%.0e", "%#.0e",
+        "%.3e", "%.40e", "%.800e", "%g", "%.0g", "%.1g", "%.3g", "%#g", "%#.3g", "%.17g", "%.40g",
+        "%G", "%E", "%F", "%+.3e", "% g", "%-12.4g|", "%015.3f", "%#.2g", "%#.4g",
+    ] {
+        for val in [
+            0.0,
+            -0.0,
+            0.1,
+            0.5,
+            1.5,
+            2.5,
+            0.125,
+            0.0001,
+            0.00001,
+            0.000099999,
+            9.9999,
+            99999.5,
+            99.5,
+            9999.95,
+            100000.0,
+            999999.5,
+            1e15,
+            1e16,
+            1e22,
+            1e23,
+            123456789.0,
+            -46.38,
+            1e300,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            f64::EPSILON,
+            5e-324,
+            1e-310,
+            std::f64::consts::PI,
+        ] {
+            check_fmt(fmt, val);
+        }
+    }
+}
+
+/// Keeps the start of the output, then fails, for huge widths and precisions
+struct Head(String);
+
+impl std::fmt::Write for Head {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        if self.0.len() >= 40 {
+            return Err(std::fmt::Error);
+        }
+        self.0.push_str(s);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_float_max_precision() {
+    // glibc fails with EOVERFLOW, so compare the start of the output with a
+    // smaller precision
+    for (fmt, short_fmt) in [
+        ("%.2147483647f", "%.1100f"),
+        ("%.2147483647e", "%.1100e"),
+        ("%#.2147483647g", "%#.1100g"),
+    ] {
+        for val in [1.0, 1e-5, 0.001, 1e300, 5e-324] {
+            let mut head = Head(String::new());
+            let res = vwritef(&mut head, fmt, &[&val]);
+            assert_eq!(res, Err(PrintfError::WriteError));
+            let expected = sprintf!(short_fmt, val).unwrap();
+            assert_eq!(head.0[..30], expected[..30], "{} {}", fmt, val);
+        }
+    }
+    // without `#`, %g stops at the last non-zero digit
+    check_fmt("%.1100g", 0.001);
+    assert_eq!(sprintf!("%.2147483647g", 0.001), sprintf!("%.1100g", 0.001));
+}
+
+#[test]
+fn test_hex_float() {
+    for fmt in [
+        "%a", "%A", "%.0a", "%.1a", "%.2a", "%.15a", "%#.0a", "%#a", "%010a", "%-12A|", "%+.3a",
+        "% a", "%20.4a",
+    ] {
+        for val in [
+            0.0,
+            -0.0,
+            1.0,
+            1.5,
+            0.1,
+            -3.0,
+            255.5,
+            1.03125,
+            1.09375,
+            1.96875,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            1e-310,
+            5e-324,
+            f64::from_bits(0x000f_ffff_ffff_ffff),
+        ] {
+            check_fmt(fmt, val);
+        }
+        // f32 is promoted to f64 in C varargs
+        assert_eq!(
+            sprintf!(fmt, 0.1_f32).unwrap(),
+            sprintf!(fmt, 0.1_f32 as f64).unwrap()
+        );
+    }
+    check_fmt("%a", f64::INFINITY);
+    check_fmt("%-6A|", f64::NEG_INFINITY);
+    if cfg!(target_env = "gnu") {
+        check_fmt("%a", f64::NAN);
+        check_fmt("%A", f64::NAN);
+    }
+}
+
 #[test]
 fn test_str() {
     check_fmt_s("test %% with string: %s yay\n", "FOO");
@@ -142,12 +623,253 @@ fn test_str() {
     assert_eq!(sprintf!("%1.8s", "𒀀𒀀𒀀").unwrap(), "𒀀𒀀");
 }
 
+#[test]
+fn test_bytes() {
+    check_fmt_bytes(b"%s", b"caf\xe9");
+    check_fmt_bytes(b"\xff%s\xfe", b"abc");
+    check_fmt_bytes(b"[%8s]", b"\xe9t\xe9");
+    check_fmt_bytes(b"[%-8s]", b"\xe9t\xe9");
+    check_fmt_bytes(b"%.1s", "𒀀".as_bytes()); // splits the character, like C
+    check_fmt_bytes(b"%1.3s", b"AB\x80\x80");
+    let c_string = CString::new(&b"caf\xe9"[..]).unwrap();
+    assert_eq!(
+        vsprintf_bytes(b"%5s|%d", &[&c_string.as_c_str(), &42]),
+        Ok(b" caf\xe9|42".to_vec())
+    );
+    // %c converts an `int` to `unsigned char` and writes it as it is
+    for fmt in [&b"%c"[..], b"[%-3c]", b"%3.0c|"] {
+        for val in [c_int::from(b'a'), 0xe9, 0xff, 200, 321, -56] {
+            let mut buf = [0_u8; 16];
+            let cfmt = CString::new(fmt).unwrap();
+            let clen = unsafe {
+                snprintf(
+                    buf.as_mut_ptr() as *mut c_char,
+                    buf.len(),
+                    cfmt.as_ptr(),
+                    val,
+                )
+            };
+            let c_result = &buf[..clen as usize];
+            assert_eq!(vsprintf_bytes(fmt, &[&val]).unwrap(), c_result);
+            assert_eq!(vsprintf_bytes(fmt, &[&(val as u8)]).unwrap(), c_result);
+            assert_eq!(vsprintf_bytes(fmt, &[&(val as i8)]).unwrap(), c_result);
+            assert_eq!(vsprintf_bytes(fmt, &[&(val as i16)]).unwrap(), c_result);
+        }
+    }
+    // text arguments are written as usual
+    assert_eq!(
+        vsprintf_bytes(b"%s %c %.2f", &[&"\u{e9}", &'x', &2.5]),
+        Ok("\u{e9} x 2.50".as_bytes().to_vec())
+    );
+    // a specifier can't contain invalid UTF-8
+    assert!(matches!(
+        vsprintf_bytes(b"%\xffd", &[&1]),
+        Err(PrintfError::ParseError(_))
+    ));
+    // without the byte-oriented functions, byte strings must be UTF-8
+    let valid: &[u8] = b"ok";
+    let invalid: &[u8] = b"caf\xe9";
+    assert_eq!(sprintf!("%s", valid), Ok("ok".to_string()));
+    assert!(matches!(
+        sprintf!("%s", invalid),
+        Err(PrintfError::WrongType(_))
+    ));
+    assert!(matches!(
+        sprintf!("%s", c_string.as_c_str()),
+        Err(PrintfError::WrongType(_))
+    ));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_os_str() {
+    use std::ffi::{OsStr, OsString};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+
+    let name = OsStr::from_bytes(b"r\xe9sum\xe9.txt");
+    let path = Path::new(name);
+    assert_eq!(
+        vsprintf_bytes(b"%s: %-14s|", &[&name, &path]),
+        Ok(b"r\xe9sum\xe9.txt: r\xe9sum\xe9.txt    |".to_vec())
+    );
+    assert_eq!(
+        vsprintf_bytes(b"%.3s", &[&OsString::from(name)]),
+        Ok(b"r\xe9s".to_vec())
+    );
+    assert_eq!(
+        vsprintf_bytes(b"%s", &[&PathBuf::from("/tmp")]),
+        Ok(b"/tmp".to_vec())
+    );
+    assert!(matches!(
+        sprintf!("%s", path),
+        Err(PrintfError::WrongType(_))
+    ));
+    assert_eq!(
+        sprintf!("%s", Path::new("/tmp/x")),
+        Ok("/tmp/x".to_string())
+    );
+}
+
+#[test]
+fn test_wide() {
+    check_fmt_ls("%ls", "wide");
+    check_fmt_ls("[%8ls]", "wide");
+    check_fmt_ls("[%-8.3ls]", "wide");
+    check_fmt_ls("%S!", "wide");
+    check_fmt_w("%d", 42);
+    check_fmt_w("[%-6x]", 255);
+    check_fmt_w("%.3f", 2.5);
+    check_fmt_w("%lc", 'x');
+    check_fmt_w("%5c", b'x');
+    let text = wide("text");
+    check_fmt_w("[%ls]", &text[..text.len() - 1]);
+    check_fmt_w("[%6.2ls]", &text[..text.len() - 1]);
+
+    // precision and width count UTF-16 code units, or characters in UTF-32
+    let clef: Vec<u16> = "\u{1d11e}ab".encode_utf16().collect();
+    let fmt16: Vec<u16> = "[%.3ls|%5s] %n".encode_utf16().collect();
+    let count = Cell::new(0);
+    let s = vswprintf_utf16(&fmt16, &[&&clef[..], &"\u{e9}", &count]).unwrap();
+    assert_eq!(String::from_utf16(&s).unwrap(), "[\u{1d11e}a|    \u{e9}] ");
+    assert_eq!(count.get(), 12);
+    let fmt32 = wide("[%.3ls|%5s] %n");
+    let s = vswprintf_utf32(&fmt32[..fmt32.len() - 1], &[&&clef[..], &"\u{e9}", &count]).unwrap();
+    assert_eq!(s, wide("[\u{1d11e}ab|    \u{e9}] ")[..12]);
+    assert_eq!(count.get(), 12);
+
+    // invalid UTF-16 and UTF-32 are rejected
+    let lone_surrogate: &[u16] = &[0xd800];
+    assert!(matches!(
+        sprintf!("%ls", lone_surrogate),
+        Err(PrintfError::WrongType(_))
+    ));
+    assert!(matches!(
+        vswprintf_utf16(&[0xdc00], &[]),
+        Err(PrintfError::ParseError(_))
+    ));
+    assert!(matches!(
+        vswprintf_utf32(&[0x110000], &[]),
+        Err(PrintfError::ParseError(_))
+    ));
+
+    // in strict mode, `%ls` takes a wide string and `%s` a narrow one
+    let options = FormatOptions {
+        strict: true,
+        ..Default::default()
+    };
+    let utf16: &dyn Printf = &&clef[..];
+    assert!(options
+        .vsprintf("%ls%S%lc%C", &[utf16, utf16, &'a', &1_u32])
+        .is_ok());
+    assert!(matches!(
+        options.vsprintf("%s", &[utf16]),
+        Err(PrintfError::WrongType(_))
+    ));
+    assert!(matches!(
+        options.vsprintf("%ls", &[&"a"]),
+        Err(PrintfError::WrongType(_))
+    ));
+}
+
+#[test]
+fn test_smart_pointers() {
+    use std::borrow::Cow;
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    let s = "str";
+    let mut n = 42;
+    assert_eq!(sprintf!("%s %s %d", &s, &&s, &mut n).unwrap(), "str str 42");
+    let boxed: Box<str> = "box".into();
+    let rc: Rc<str> = "rc".into();
+    let arc = Arc::new(String::from("arc"));
+    let cow: Cow<str> = Cow::Borrowed("cow");
+    let owned: Cow<[u8]> = Cow::Owned(b"owned".to_vec());
+    assert_eq!(
+        sprintf!("%s %s %s %s %s", boxed, rc, arc, cow, owned).unwrap(),
+        "box rc arc cow owned"
+    );
+    let dynamic: Vec<Box<dyn Printf>> = vec![Box::new(1.5), Box::new('x')];
+    assert_eq!(
+        sprintf!("%.1f %c", dynamic[0], dynamic[1]).unwrap(),
+        "1.5 x"
+    );
+    let count = Rc::new(Cell::new(0));
+    let width: &i32 = &4;
+    assert_eq!(sprintf!("%*d%n", width, 7, count).unwrap(), "   7");
+    assert_eq!(count.get(), 4);
+}
+
+#[test]
+fn test_option() {
+    for fmt in [
+        "%s", "%10s", "%-8s|", "%.3s", "%.6s", "%8.2s", "%5.5s", "%p", "%10p", "%-8p|", "%.3p",
+    ] {
+        let our_result = sprintf!(fmt, None::<&str>).unwrap();
+        let mut buf = vec![0_u8; our_result.len() + 1];
+        let cfmt = CString::new(fmt).unwrap();
+        let clen: usize = unsafe {
+            snprintf(
+                buf.as_mut_ptr() as *mut c_char,
+                buf.len(),
+                cfmt.as_ptr(),
+                std::ptr::null::<c_char>(),
+            )
+        }
+        .try_into()
+        .unwrap();
+        buf.truncate(clen);
+        assert_eq!(our_result, String::from_utf8(buf).unwrap());
+    }
+    assert_eq!(
+        sprintf!("%s %5d %.1f", Some("a"), Some(42), Some(1.25)).unwrap(),
+        "a    42 1.2"
+    );
+    // glibc only writes `None` for `%s` and `%p`
+    for fmt in ["%d", "%x", "%f", "%c"] {
+        assert!(matches!(
+            sprintf!(fmt, None::<i32>),
+            Err(PrintfError::WrongType(_))
+        ));
+    }
+    assert!(Format::<(Option<i32>,)>::new("%d").is_err());
+    assert!(Format::<(Option<i32>,)>::new("%p").is_ok());
+    assert!(matches!(
+        sprintf!("%*d", None::<i32>, 1),
+        Err(PrintfError::WrongType(_))
+    ));
+    assert!(matches!(
+        sprintf!("%n", None::<Cell<i32>>),
+        Err(PrintfError::WrongType(_))
+    ));
+    let options = FormatOptions {
+        null_text: "NULL",
+        strict: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        options
+            .vsprintf(
+                "%s|%-6s|%.3s|%p",
+                &[&None::<&str>, &None::<&str>, &None::<&str>, &None::<&str>]
+            )
+            .unwrap(),
+        "NULL|NULL  ||(nil)"
+    );
+    assert!(matches!(
+        options.vsprintf("%d", &[&None::<i32>]),
+        Err(PrintfError::WrongType(_))
+    ));
+}
+
 #[test]
 fn test_char() {
     check_fmt("%c", 'x');
     check_fmt("%c", b'x');
     check_fmt("%c", b'x' as c_char);
     check_fmt("%c", u16::try_from('x').unwrap());
+    #[allow(clippy::unnecessary_fallible_conversions)]
     check_fmt("%c", u32::try_from('x').unwrap());
     check_fmt("%4c", 'A');
     check_fmt("%-4cX", 'A');
@@ -158,7 +880,323 @@ fn test_sanity() {
     // u8 must not misinterpret bytes from multi-byte UTF-8 characters
     let bytes = "∆".as_bytes();
     assert!(bytes.len() > 1);
-    assert_eq!(sprintf!("%c", bytes[0]), Err(PrintfError::WrongType));
+    assert!(matches!(
+        sprintf!("%c", bytes[0]),
+        Err(PrintfError::WrongType(_))
+    ));
+}
+
+#[test]
+fn test_locale() {
+    // the C locale doesn't group digits
+    for fmt in ["%'d", "%'.10d", "%'15d", "%'015d", "%I'd"] {
+        check_fmt(fmt, 1234567);
+    }
+    check_fmt("%'.2f", 1234567.891);
+    check_fmt("%'g", 1234567.0);
+
+    let de_de = Locale {
+        decimal_point: ",",
+        thousands_sep: ".",
+        grouping: &[3],
+        ..Locale::C
+    };
+    let fr_fr = Locale {
+        thousands_sep: "\u{202f}",
+        ..de_de
+    };
+    let hi_in = Locale {
+        thousands_sep: ",",
+        grouping: &[3, 2],
+        digits: ["०", "१", "२", "३", "४", "५", "६", "७", "८", "९"],
+        ..Locale::C
+    };
+    let fmt = |locale: Locale, fmt: &str, arg: &dyn Printf| {
+        let options = FormatOptions {
+            locale,
+            ..Default::default()
+        };
+        options.vsprintf(fmt, &[arg]).unwrap()
+    };
+
+    // expected values from glibc
+    assert_eq!(fmt(de_de, "[%'d]", &1234567), "[1.234.567]");
+    assert_eq!(fmt(de_de, "[%'.10d]", &-1234), "[-000001.234]");
+    assert_eq!(fmt(de_de, "[%'15d]", &1234567), "[      1.234.567]");
+    assert_eq!(fmt(de_de, "[%'015d]", &1234567), "[0000001.234.567]");
+    assert_eq!(fmt(de_de, "[%'-15d|]", &1234567), "[1.234.567      |]");
+    assert_eq!(fmt(de_de, "[%'x]", &1234567), "[12d.687]");
+    assert_eq!(fmt(de_de, "[%'u]", &4000000000_u32), "[4.000.000.000]");
+    assert_eq!(fmt(de_de, "[%'.0d]", &0), "[]");
+    assert_eq!(fmt(de_de, "[%d]", &1234567), "[1234567]");
+    assert_eq!(fmt(de_de, "[%'.2f]", &1234567.891), "[1.234.567,89]");
+    assert_eq!(fmt(de_de, "[%'f]", &-1234.5), "[-1.234,500000]");
+    assert_eq!(fmt(de_de, "[%'e]", &1234.5), "[1,234500e+03]");
+    assert_eq!(fmt(de_de, "[%'g]", &1234567.0), "[1,23457e+06]");
+    assert_eq!(fmt(de_de, "[%'#.0f]", &123.0), "[123,]");
+    assert_eq!(fmt(de_de, "[%'015.2f]", &1234.5), "[00000001.234,50]");
+    assert_eq!(fmt(de_de, "[%'.10g]", &12345678.0), "[12.345.678]");
+    assert_eq!(fmt(de_de, "[%a]", &1.5), "[0x1,8p+0]");
+    assert_eq!(fmt(de_de, "[%f]", &f64::INFINITY), "[inf]");
+    assert_eq!(fmt(fr_fr, "[%'d]", &1234567), "[1\u{202f}234\u{202f}567]");
+    assert_eq!(fmt(fr_fr, "[%'.10d]", &1234), "[0001\u{202f}234]");
+    assert_eq!(fmt(hi_in, "[%'d]", &1234567), "[12,34,567]");
+    assert_eq!(fmt(hi_in, "[%I'd]", &1234567), "[१२,३४,५६७]");
+    assert_eq!(fmt(hi_in, "[%I.2f]", &2.75), "[२.७५]");
+    assert_eq!(fmt(hi_in, "[%Ie]", &1.5), "[१.५०००००e+००]");
+    assert_eq!(fmt(hi_in, "[%I'x]", &1234567), "[1,2d,687]");
+    assert_eq!(fmt(hi_in, "[%I5d]", &42), "[४२]");
+    assert_eq!(fmt(hi_in, "[%I08d]", &-12), "[-0१२]");
+
+    let nl = Locale {
+        inf: "oneindig",
+        nan: "nan",
+        ..de_de
+    };
+    assert_eq!(fmt(nl, "[%-10f]", &f64::INFINITY), "[oneindig  ]");
+    assert_eq!(fmt(nl, "[%F]", &-f64::INFINITY), "[-ONEINDIG]");
+}
+
+#[test]
+fn test_text_width() {
+    let fmt = |text_width: TextWidth, fmt: &str, args: &[&dyn Printf]| {
+        let options = FormatOptions {
+            text_width,
+            ..Default::default()
+        };
+        options.vsprintf(fmt, args).unwrap()
+    };
+    let text = "Grüße";
+    assert_eq!(fmt(TextWidth::Bytes, "[%-8s]", &[&text]), "[Grüße ]");
+    assert_eq!(fmt(TextWidth::Chars, "[%-8s]", &[&text]), "[Grüße   ]");
+    assert_eq!(fmt(TextWidth::Bytes, "[%.3s]", &[&text]), "[Gr]");
+    assert_eq!(fmt(TextWidth::Chars, "[%.3s]", &[&text]), "[Grü]");
+    assert_eq!(fmt(TextWidth::Chars, "[%8.3s]", &[&text]), "[     Grü]");
+    assert_eq!(fmt(TextWidth::Chars, "[%3c]", &[&'ü']), "[  ü]");
+    assert_eq!(fmt(TextWidth::Chars, "[%3c]", &[&0xfc_u32]), "[  ü]");
+    let c_string = CString::new(text).unwrap();
+    assert_eq!(fmt(TextWidth::Chars, "[%6s]", &[&c_string]), "[ Grüße]");
+}
+
+#[cfg(feature = "unicode")]
+#[test]
+fn test_text_width_columns() {
+    let fmt = |fmt: &str, args: &[&dyn Printf]| {
+        let options = FormatOptions {
+            text_width: TextWidth::Columns,
+            ..Default::default()
+        };
+        options.vsprintf(fmt, args).unwrap()
+    };
+    assert_eq!(
+        fmt("[%-8s|%8s]", &[&"日本語", &"Grüße"]),
+        "[日本語  |   Grüße]"
+    );
+    assert_eq!(fmt("[%-4c|%4c]", &[&'日', &'a']), "[日  |   a]");
+    // decomposed: e + combining acute accent
+    let cafe = "cafe\u{301}";
+    assert_eq!(fmt("[%6s]", &[&cafe]), "[  cafe\u{301}]");
+    assert_eq!(fmt("[%.4s]", &[&cafe]), "[cafe\u{301}]");
+    assert_eq!(fmt("[%.3s]", &[&cafe]), "[caf]");
+    // the flag emoji is one grapheme cluster, two columns wide
+    let flag = "\u{1f1e9}\u{1f1ea}!";
+    assert_eq!(fmt("[%.1s]", &[&flag]), "[\u{1f1e9}\u{1f1ea}]");
+    assert_eq!(fmt("[%4s]", &[&flag]), "[ \u{1f1e9}\u{1f1ea}!]");
+}
+
+#[test]
+fn test_star_args() {
+    for width in [-12, -1, 0, 3, 12] {
+        for fmt in ["%*d|", "%-*d|", "%0*d|", "%+0*x|"] {
+            check_fmt_2(fmt, width, 42);
+        }
+        check_fmt_2("%0*.3f|", width, 4.2);
+        check_fmt_2("%*c|", width, 'x');
+        check_fmt_2("%2$*1$d|", width, 7);
+    }
+    for precision in [-12, -1, 0, 3] {
+        for fmt in ["%.*f|", "%.*e|", "%#.*g|", "%.*a|"] {
+            check_fmt_2(fmt, precision, 42.5);
+        }
+        for fmt in ["%.*d|", "%08.*d|", "%.*x|"] {
+            check_fmt_2(fmt, precision, 0);
+            check_fmt_2(fmt, precision, 42);
+        }
+    }
+
+    // any integer type that fits in an int can be used
+    assert_eq!(sprintf!("%*d|", 5_u64, 1).unwrap(), "    1|");
+    assert_eq!(sprintf!("%*d|", -5_i8, 1).unwrap(), "1    |");
+    assert_eq!(sprintf!("%.*f", 2_usize, 1.0).unwrap(), "1.00");
+    assert_eq!(sprintf!("%.*f", -2_i64, 1.0).unwrap(), "1.000000");
+    assert!(matches!(
+        sprintf!("%*d", u64::MAX, 1),
+        Err(PrintfError::WrongType(_))
+    ));
+    let mut head = Head(String::new());
+    let res = vwritef(&mut head, "%*d|", &[&i32::MIN, &1]);
+    assert_eq!(res, Err(PrintfError::WriteError));
+    assert!(head.0.starts_with("1 ") && head.0.trim_end() == "1");
+    assert!(matches!(
+        sprintf!("%*d", 1.0, 1),
+        Err(PrintfError::WrongType(_))
+    ));
+}
+
+#[test]
+fn test_numbered_args() {
+    check_fmt_2("%2$d %1$d", 1, 2);
+    check_fmt_2("%1$d %1$x %2$f", 255, 1.5);
+    check_fmt_2("%2$*1$d|", 8, -42);
+    check_fmt_2("%2$.*1$f", 2, 5.4321);
+    check_fmt_2("%1$-*2$d|", 42, 6);
+    check_fmt_2("%*d|", 5, 42);
+    check_fmt_2("%.*f", 1, 2.34);
+    check_fmt_2("%2$c%%%1$c", 'x', 'y');
+
+    // numbered and unnumbered arguments cannot be mixed
+    assert!(matches!(
+        sprintf!("%1$d %d", 1, 2),
+        Err(PrintfError::ParseError(_))
+    ));
+    assert!(matches!(
+        sprintf!("%1$*d", 1, 2),
+        Err(PrintfError::ParseError(_))
+    ));
+    // all arguments must be used
+    assert!(matches!(
+        sprintf!("%2$d", 1, 2),
+        Err(PrintfError::ParseError(_))
+    ));
+    assert_eq!(sprintf!("%1$d", 1, 2), Err(PrintfError::TooManyArgs));
+    assert!(matches!(
+        sprintf!("%1$d %2$d", 1),
+        Err(PrintfError::NotEnoughArgs(_))
+    ));
+    // arguments are numbered from 1
+    assert!(matches!(
+        sprintf!("%0$d", 1),
+        Err(PrintfError::ParseError(_))
+    ));
+
+    // many numbered arguments, referenced in reverse
+    let values: Vec<i32> = (1..=5000).collect();
+    let args: Vec<&dyn Printf> = values.iter().map(|v| v as &dyn Printf).collect();
+    let format: String = (1..=5000).rev().map(|n| format!("%{}$d,", n)).collect();
+    let expected: String = (1..=5000).rev().map(|n| format!("{},", n)).collect();
+    assert_eq!(vsprintf(&format, &args).unwrap(), expected);
+    // the first unused one is reported, even past the number of references
+    let format = format.replacen("%4000$d,", "", 1);
+    assert!(matches!(
+        vsprintf(&format, &args),
+        Err(PrintfError::ParseError(ParseError {
+            reason: ParseErrorReason::UnusedArgNumber(4000),
+            ..
+        }))
+    ));
+    assert!(matches!(
+        vsprintf("%1$d %3$d %1000000000$d", &args),
+        Err(PrintfError::ParseError(ParseError {
+            reason: ParseErrorReason::UnusedArgNumber(2),
+            ..
+        }))
+    ));
+}
+
+#[test]
+fn test_count() {
+    for fmt in ["%2$n|%1$d", "%5d%n", "%-+8d|%n", "%.3d%n%%"] {
+        let n = Cell::new(-1_i32);
+        let our_result = sprintf!(fmt, 42, n).unwrap();
+        let mut cn: c_int = -1;
+        let mut buf = vec![0_u8; our_result.len() + 1];
+        let cfmt = CString::new(fmt).unwrap();
+        let nptr = &mut cn as *mut c_int;
+        let clen = unsafe {
+            if fmt.starts_with("%n") {
+                snprintf(
+                    buf.as_mut_ptr() as *mut c_char,
+                    buf.len(),
+                    cfmt.as_ptr(),
+                    nptr,
+                    42,
+                )
+            } else {
+                snprintf(
+                    buf.as_mut_ptr() as *mut c_char,
+                    buf.len(),
+                    cfmt.as_ptr(),
+                    42,
+                    nptr,
+                )
+            }
+        };
+        buf.truncate(clen as usize);
+        assert_eq!(our_result, String::from_utf8(buf).unwrap());
+        assert_eq!(n.get(), cn);
+    }
+
+    // the count is stored truncated to the argument type, and counts bytes
+    let n8 = Cell::new(0_u8);
+    let n = AtomicUsize::new(0);
+    let s = sprintf!("%300s%hhn\u{263a}%n", "", n8, n).unwrap();
+    assert_eq!(s.len(), 303);
+    assert_eq!(n8.get(), 44);
+    assert_eq!(n.load(Ordering::Relaxed), 303);
+    // vsnprintf counts the output that didn't fit
+    let n = Cell::new(0_usize);
+    let mut buf = [0_u8; 4];
+    vsnprintf(&mut buf, Truncation::Bytes, "%s%n", &[&"hello", &n]).unwrap();
+    assert_eq!(n.get(), 5);
+
+    // %n can be forbidden
+    let options = FormatOptions {
+        forbid_count: true,
+        ..Default::default()
+    };
+    let mut s = String::new();
+    let res = options.vwritef(&mut s, "abc%n", &[&n]);
+    assert!(matches!(res, Err(PrintfError::ParseError(_))));
+    assert_eq!(s, "");
+    // only cells can be used for %n
+    assert!(matches!(sprintf!("%n", 0), Err(PrintfError::WrongType(_))));
+}
+
+#[test]
+fn test_snprintf() {
+    let fmt = "%s|%5d|%.3f";
+    let cfmt = CString::new(fmt).unwrap();
+    let carg = CString::new("ab∆c").unwrap();
+    let args: [&dyn Printf; 3] = [&"ab∆c", &-42, &1.5];
+    for n in 0..24 {
+        let mut ours = vec![0xff_u8; n];
+        let our_len = vsnprintf(&mut ours, Truncation::Bytes, fmt, &args).unwrap();
+        let mut theirs = vec![0xff_u8; n];
+        let c_len = unsafe {
+            snprintf(
+                theirs.as_mut_ptr() as *mut c_char,
+                n,
+                cfmt.as_ptr(),
+                carg.as_ptr(),
+                -42,
+                1.5,
+            )
+        };
+        assert_eq!(our_len, usize::try_from(c_len).unwrap());
+        assert_eq!(ours, theirs);
+    }
+
+    // truncation at character boundaries ("∆" is 3 bytes)
+    let mut buf = [0xff_u8; 5];
+    assert_eq!(
+        vsnprintf(&mut buf, Truncation::CharBoundary, fmt, &args),
+        Ok(18)
+    );
+    assert_eq!(&buf, b"ab\0\xff\xff");
+    assert_eq!(
+        vsnprintf(&mut [], Truncation::CharBoundary, fmt, &args),
+        Ok(18)
+    );
 }
 
 #[test]
@@ -171,6 +1209,12 @@ fn test_ptr() {
     assert_eq!(size_of::<usize>(), size_of::<*const u8>(),);
     check_fmt("%p", ptr_const);
     check_fmt("%p", ptr_mut);
+    for fmt in ["%20p", "%-20p|", "%+p", "% p", "%.20p", "%020p", "%#p"] {
+        check_fmt(fmt, ptr_const);
+    }
+    for fmt in ["%p", "%10p", "%-10p|", "%.3p", "%010p", "%+p"] {
+        check_fmt(fmt, core::ptr::null::<u8>());
+    }
 
     // numeric: works the same as libc if you use the correct length specifier
     if size_of::<usize>() == size_of::<u64>() {
@@ -185,3 +1229,372 @@ fn test_ptr() {
         check_fmt("%#x", ptr_mut);
     }
 }
+
+#[test]
+fn test_display_roundtrip() {
+    for fmt in [
+        "%d %i %u %c %C %s %S %x %p %%",
+        "%-#0 +10.3hhd",
+        "%*.*lld",
+        "%3$*1$.*2$Lf",
+        "%zu %ju %td %qd %Zd",
+        "%#010b %B %w32d %wf16u",
+        "%'d %'I.3f",
+    ] {
+        let parsed = sprintf::parser::parse_format_string(fmt).unwrap();
+        let out: String = parsed.iter().map(|e| e.to_string()).collect();
+        assert_eq!(out, fmt);
+    }
+}
+
+#[test]
+fn test_format_handle() {
+    let format = Format::<(i32, &str)>::new("%5d|%-4s|").unwrap();
+    for (n, s) in [(42, "ab"), (-7, "xyz")] {
+        assert_eq!(
+            format.render((n, s)),
+            vsprintf("%5d|%-4s|", &[&n, &s]).unwrap()
+        );
+    }
+    assert_eq!(format.render((1, "a")), "    1|a   |");
+
+    // a format stored with 'static argument types takes shorter-lived ones
+    let format: Format<(&'static str,)> = Format::new("<%s>").unwrap();
+    let s = String::from("temp");
+    assert_eq!(format.render((&s[..],)), "<temp>");
+
+    assert_eq!(Format::<()>::new("100%%").unwrap().render(()), "100%");
+    assert_eq!(
+        Format::<(u16, u64, f64)>::new("%2$*1$lu %3$.1f")
+            .unwrap()
+            .render((5, 12, 0.25)),
+        "   12 0.2"
+    );
+    assert_eq!(
+        Format::<(Option<&str>, Box<char>, *const u8)>::new("%s %c %p")
+            .unwrap()
+            .render((None, Box::new('x'), std::ptr::null())),
+        "(null) x (nil)"
+    );
+    let count = Cell::new(0);
+    let mut out = String::new();
+    Format::<(&str, &Cell<i32>)>::new("%s%n")
+        .unwrap()
+        .write(&mut out, ("four", &count))
+        .unwrap();
+    assert_eq!((out.as_str(), count.get()), ("four", 4));
+
+    assert!(matches!(
+        Format::<(i32,)>::new("%d %d").unwrap_err(),
+        PrintfError::NotEnoughArgs(_)
+    ));
+    assert_eq!(
+        Format::<(i32, i32)>::new("%d").unwrap_err(),
+        PrintfError::TooManyArgs
+    );
+    assert!(matches!(
+        Format::<(i32,)>::new("%y").unwrap_err(),
+        PrintfError::ParseError(_)
+    ));
+    for format in ["%f", "%s", "%c", "%n"] {
+        assert!(matches!(
+            Format::<(i32,)>::new(format).unwrap_err(),
+            PrintfError::WrongType(_)
+        ));
+    }
+    // only conversions that work for every value are accepted
+    assert!(matches!(
+        Format::<(u8,)>::new("%c").unwrap_err(),
+        PrintfError::WrongType(_)
+    ));
+    assert!(matches!(
+        Format::<(i64, i32)>::new("%*d").unwrap_err(),
+        PrintfError::WrongType(_)
+    ));
+    assert!(matches!(
+        Format::<(Option<&Cell<i32>>,)>::new("%n").unwrap_err(),
+        PrintfError::WrongType(_)
+    ));
+}
+
+#[test]
+fn test_format_handle_min_width() {
+    // as wide as possible, and left-adjusted
+    let format = Format::<(i32, i32)>::new("%*d").unwrap();
+    let mut head = Head(String::new());
+    assert_eq!(format.write(&mut head, (i32::MIN, 1)), Err(std::fmt::Error));
+    assert_eq!(head.0, format!("{:<40}", 1));
+}
+
+#[test]
+fn test_compiled_format() {
+    struct Template {
+        format: CompiledFormat,
+    }
+    let template = {
+        let source = String::from("%-5s|%+.2e|%#x|%%");
+        Template {
+            format: CompiledFormat::new(&source).unwrap(),
+        }
+    };
+    let args: [&dyn Printf; 3] = [&"id", &1234.5, &255];
+    assert_eq!(
+        template.format.vsprintf(&args).unwrap(),
+        vsprintf("%-5s|%+.2e|%#x|%%", &args).unwrap()
+    );
+    assert_eq!(template.format.to_string(), "%-5s|%+.2e|%#x|%%");
+    assert_eq!(template.format.arg_count(), 3);
+    assert!(matches!(
+        template.format.vsprintf(&args[..2]),
+        Err(PrintfError::NotEnoughArgs(_))
+    ));
+
+    let format = CompiledFormat::new("%*.*f %s").unwrap();
+    let plan: Vec<_> = format.arg_uses().map(|u| (u.index, u.role)).collect();
+    assert_eq!(
+        plan,
+        [
+            (0, ArgRole::Width),
+            (1, ArgRole::Precision),
+            (2, ArgRole::Value),
+            (3, ArgRole::Value)
+        ]
+    );
+    let spec = format.arg_uses().nth(3).unwrap().spec;
+    assert_eq!(spec.conversion_type, ConversionType::String);
+    assert_eq!(
+        format.vsprintf(&[&8, &3, &2.5, &"x"]).unwrap(),
+        "   2.500 x"
+    );
+
+    let format = CompiledFormat::new("%2$s %1$s %2$s").unwrap();
+    assert_eq!(format.arg_count(), 2);
+    assert_eq!(
+        format.arg_uses().map(|u| u.index).collect::<Vec<_>>(),
+        [1, 0, 1]
+    );
+    assert_eq!(format.vsprintf(&[&"a", &"b"]).unwrap(), "b a b");
+    assert_eq!(format, CompiledFormat::new("%2$s %1$s %2$s").unwrap());
+
+    // verbatim text longer than fits in one element, with multibyte chars
+    let source = "é€".repeat(40) + "%d" + &"x".repeat(200) + "%%";
+    let format = CompiledFormat::new(&source).unwrap();
+    assert_eq!(format.to_string(), source);
+    let debug = format!("{:?}", CompiledFormat::new("ab%d").unwrap());
+    assert!(debug.contains(r#"Verbatim("ab")"#), "{}", debug);
+    assert_eq!(
+        format.vsprintf(&[&-1]).unwrap(),
+        source.replace("%d", "-1").replace("%%", "%")
+    );
+    assert!(matches!(
+        format.vsprintf(&[&1, &2]),
+        Err(PrintfError::TooManyArgs)
+    ));
+    let options = FormatOptions {
+        forbid_count: true,
+        ..Default::default()
+    };
+    let count = Cell::new(0);
+    assert!(matches!(
+        CompiledFormat::new("ab%n")
+            .unwrap()
+            .vsprintf_with(&options, &[&count]),
+        Err(PrintfError::ParseError(ParseError { offset: 2, .. }))
+    ));
+
+    let options = FormatOptions {
+        apply_length_modifiers: true,
+        ..Default::default()
+    };
+    let format = CompiledFormat::new("%hhx").unwrap();
+    assert_eq!(format.vsprintf_with(&options, &[&300]).unwrap(), "2c");
+
+    assert!(matches!(
+        CompiledFormat::new("%1$d %d"),
+        Err(PrintfError::ParseError(_))
+    ));
+    assert!(matches!(
+        CompiledFormat::new("%y"),
+        Err(PrintfError::ParseError(_))
+    ));
+}
+
+#[cfg(feature = "cache")]
+#[test]
+fn test_cache() {
+    use sprintf::cache;
+
+    // every test runs in its own thread, with its own cache
+    cache::clear();
+    cache::set_capacity(2);
+    cache::prewarm(["%d", "%s"]).unwrap();
+    assert!(cache::contains("%d") && cache::contains("%s"));
+    assert!(matches!(
+        cache::prewarm(["%y"]),
+        Err(PrintfError::ParseError(_))
+    ));
+    assert_eq!(
+        cache::stats(),
+        cache::CacheStats {
+            len: 2,
+            capacity: 2,
+            hits: 0,
+            misses: 0
+        }
+    );
+
+    assert_eq!(vsprintf("%d", &[&1]).unwrap(), "1");
+    let mut s = String::new();
+    vwritef(&mut s, "%s", &[&"x"]).unwrap();
+    assert_eq!(s, "x");
+    assert_eq!((cache::stats().hits, cache::stats().misses), (2, 0));
+
+    // "%d" was used less recently than "%s"
+    assert_eq!(vsprintf("%x", &[&255]).unwrap(), "ff");
+    assert!(!cache::contains("%d") && cache::contains("%s") && cache::contains("%x"));
+    assert_eq!((cache::stats().hits, cache::stats().misses), (2, 1));
+
+    // errors are the same as without the cache
+    assert!(matches!(
+        vsprintf("%y", &[&1]),
+        Err(PrintfError::ParseError(_))
+    ));
+    assert!(matches!(
+        vsprintf("%x", &[]),
+        Err(PrintfError::NotEnoughArgs(_))
+    ));
+    assert_eq!(cache::stats().len, 2);
+
+    cache::set_capacity(0);
+    assert_eq!(vsprintf("%x", &[&255]).unwrap(), "ff");
+    assert_eq!(cache::stats().len, 0);
+
+    // the least recently used are dropped first, however they were used
+    cache::set_capacity(4);
+    cache::prewarm(["1%d", "2%d", "3%d", "4%d", "1%d"]).unwrap();
+    assert_eq!(vsprintf("3%d", &[&0]).unwrap(), "30");
+    cache::set_capacity(3);
+    assert!(!cache::contains("2%d"));
+    cache::prewarm(["5%d", "6%d"]).unwrap();
+    let cached: Vec<_> = ["1%d", "3%d", "4%d", "5%d", "6%d"]
+        .iter()
+        .map(|f| cache::contains(f))
+        .collect();
+    assert_eq!(cached, [false, true, false, true, true]);
+    assert_eq!(vsprintf("6%d", &[&1]).unwrap(), "61");
+    assert_eq!(vsprintf("3%d", &[&1]).unwrap(), "31");
+    assert_eq!(vsprintf("5%d", &[&1]).unwrap(), "51");
+    cache::clear();
+    assert_eq!(vsprintf("5%d", &[&2]).unwrap(), "52");
+    assert_eq!((cache::stats().len, cache::stats().misses), (1, 1));
+}
+
+#[test]
+fn test_error_details() {
+    fn parse_error(res: Result<String>) -> (std::ops::Range<usize>, ParseErrorReason) {
+        match res {
+            Err(PrintfError::ParseError(e)) => (e.span(), e.reason),
+            res => panic!("expected a parse error, got {:?}", res),
+        }
+    }
+    use ParseErrorReason::*;
+    assert_eq!(parse_error(sprintf!("ab %y", 1)), (3..5, UnknownConversion));
+    assert_eq!(parse_error(sprintf!("x %-5", 1)), (2..5, DanglingPercent));
+    assert_eq!(
+        parse_error(sprintf!("%d %lhd", 1, 2)),
+        (3..6, DuplicateLengthModifier)
+    );
+    assert_eq!(
+        parse_error(sprintf!("%w7d", 1)),
+        (0..3, InvalidLengthModifier)
+    );
+    assert_eq!(parse_error(sprintf!("%0$d", 1)), (0..3, BadArgNumber));
+    assert_eq!(
+        parse_error(sprintf!("%1$d %d", 1, 2)),
+        (5..7, MixedArgNumbers)
+    );
+    assert_eq!(
+        parse_error(sprintf!("%3$d %2$d", 1, 2, 3)),
+        (0..4, UnusedArgNumber(1))
+    );
+    let options = FormatOptions {
+        forbid_count: true,
+        ..Default::default()
+    };
+    let n = Cell::new(0);
+    assert_eq!(
+        parse_error(options.vsprintf("abc%n", &[&n])),
+        (3..5, CountForbidden)
+    );
+    // pre-parsed elements are located in the string they are displayed as
+    let format: Vec<_> = parser::FormatElements::new("ab%-5d%1$n")
+        .collect::<Result<_>>()
+        .unwrap();
+    assert_eq!(
+        parse_error(vsprintfp(&format, &[&1, &n])),
+        (6..10, MixedArgNumbers)
+    );
+    let format = parser::parse_format_string("ab%-5d%n").unwrap();
+    assert_eq!(
+        parse_error(options.vsprintfp(&format, &[&1, &n])),
+        (6..8, CountForbidden)
+    );
+    // the error is displayed with its position
+    assert_eq!(
+        sprintf!("ab %y", 1).unwrap_err().to_string(),
+        "Error parsing the format string: unknown conversion at offset 3"
+    );
+    // byte and wide format strings count bytes and code units
+    assert!(matches!(
+        vsprintf_bytes(b"caf\xe9 %y", &[&1]),
+        Err(PrintfError::ParseError(ParseError { offset: 5, .. }))
+    ));
+    let mut format: Vec<u16> = "\u{1f600} %y".encode_utf16().collect();
+    assert!(matches!(
+        vswprintf_utf16(&format, &[&1]),
+        Err(PrintfError::ParseError(ParseError {
+            offset: 3,
+            len: 2,
+            ..
+        }))
+    ));
+    format[0] = 0xdc00;
+    assert_eq!(
+        vswprintf_utf16(&format, &[&1]),
+        Err(PrintfError::ParseError(ParseError {
+            offset: 0,
+            len: 1,
+            reason: InvalidEncoding
+        }))
+    );
+
+    // runtime errors tell which argument and conversion failed
+    let spec = |format| match parser::parse_format_string(format).unwrap()[..] {
+        [parser::FormatElement::Format(spec)] => spec,
+        _ => unreachable!(),
+    };
+    assert_eq!(
+        sprintf!("%d %s", 1, 2),
+        Err(PrintfError::WrongType(Some(ArgContext {
+            index: 1,
+            spec: spec("%s")
+        })))
+    );
+    assert_eq!(
+        vsprintf("%s %*d", &[&"a", &1]),
+        Err(PrintfError::NotEnoughArgs(ArgContext {
+            index: 2,
+            spec: spec("%*d")
+        }))
+    );
+    assert_eq!(
+        sprintf!("%2$d %1$.*2$f", 1.5, 2.5).unwrap_err().to_string(),
+        "Incorrect type passed as an argument: argument 2 for `%2$d`"
+    );
+    assert_eq!(
+        vsprintf("%s %x", &[&"a"]).unwrap_err().to_string(),
+        "Too few arguments passed: no argument 2 for `%x`"
+    );
+    // errors from Printf methods called directly have no context
+    assert_eq!(1.5.format(&spec("%d")), Err(PrintfError::WrongType(None)));
+}